@@ -1,17 +1,31 @@
 use crate::client::build_client;
+use crate::types::{
+    capability_set, watch_event_kind, PyCaptureTranscript, PyCollectedOutput,
+    PyDetachedProcessInfo, PyDirEntry, PyExecutableSource, PyExpectMatch, PyFileCapabilities,
+    PyFileRef, PyFileStat, PyMatchPattern, PyOutputChunk, PyProcessTreeEntry, PyReplayDivergence,
+    PyReplayReport, PySearchMatch, PySearchResult, PyWatchEvent,
+};
 use anyhow::Result;
 use bh_agent_common::{
-    AgentError, BhAgentServiceClient, EnvironmentId, FileId, FileOpenMode, FileOpenType,
-    ProcessChannel, ProcessId, Redirection, RemotePOpenConfig,
+    AgentError, ArchiveFormat, BhAgentServiceClient, ByteRange, EnvironmentId, FileCapabilities,
+    FileChunk, FileId, FileOpenMode, FileOpenType, FileTransferInfo, HashAlgorithm, LockKind,
+    NewlineMode, ProcessChannel, ProcessId, Redirection, RemotePOpenConfig, ReplayTiming,
+    SearchLimits, TextEncoding, TextErrors, WatchId,
 };
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
 use pyo3::{pyclass, pymethods, pymodule, PyResult, Python};
+use sha2::{Digest, Sha256};
+use std::fs::{File, FileTimes, OpenOptions, Permissions};
 use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
 use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tarpc::client::RpcError;
 use tarpc::context;
+use tarpc::context::Context;
 use tokio::runtime;
 
 #[pyclass]
@@ -20,6 +34,126 @@ struct BhAgentClient {
     client: BhAgentServiceClient,
 }
 
+// Size of the chunks put and get move files in, unless the caller picks another
+const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+fn sha256_file(file: &mut File) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Validates a mode string the way Python's open() does, returning the mode and whether it is
+// binary
+fn open_mode(mode: &str) -> PyResult<(FileOpenMode, bool)> {
+    let invalid = || PyRuntimeError::new_err("Invalid file mode");
+    let mut chars: Vec<char> = mode.chars().collect();
+    chars.sort_unstable();
+    if chars.windows(2).any(|pair| pair[0] == pair[1])
+        || chars.iter().any(|c| !"rwxa+bt".contains(*c))
+        || (chars.contains(&'b') && chars.contains(&'t'))
+    {
+        return Err(invalid());
+    }
+
+    let update = chars.contains(&'+');
+    let kinds: Vec<char> = chars
+        .iter()
+        .copied()
+        .filter(|c| "rwxa".contains(*c))
+        .collect();
+    let mode = match (kinds.as_slice(), update) {
+        (['r'], false) => FileOpenMode::Read,
+        (['r'], true) => FileOpenMode::ReadUpdate,
+        (['w'], false) => FileOpenMode::Write,
+        (['w'], true) => FileOpenMode::WriteUpdate,
+        (['x'], false) => FileOpenMode::ExclusiveWrite,
+        (['x'], true) => FileOpenMode::ExclusiveWriteUpdate,
+        (['a'], false) => FileOpenMode::Append,
+        (['a'], true) => FileOpenMode::AppendUpdate,
+        _ => return Err(invalid()),
+    };
+    Ok((mode, chars.contains(&'b')))
+}
+
+fn text_encoding(name: &str) -> PyResult<TextEncoding> {
+    match name.to_lowercase().replace('_', "-").as_str() {
+        "utf-8" | "utf8" => Ok(TextEncoding::Utf8),
+        "latin-1" | "latin1" | "iso-8859-1" | "iso8859-1" | "l1" => Ok(TextEncoding::Latin1),
+        "ascii" | "us-ascii" => Ok(TextEncoding::Ascii),
+        _ => Err(PyRuntimeError::new_err("Unsupported encoding")),
+    }
+}
+
+fn text_errors(name: &str) -> PyResult<TextErrors> {
+    match name {
+        "strict" => Ok(TextErrors::Strict),
+        "ignore" => Ok(TextErrors::Ignore),
+        "replace" => Ok(TextErrors::Replace),
+        "backslashreplace" => Ok(TextErrors::BackslashReplace),
+        _ => Err(PyRuntimeError::new_err("Unsupported errors handler")),
+    }
+}
+
+fn newline_mode(newline: Option<&str>) -> PyResult<NewlineMode> {
+    match newline {
+        None => Ok(NewlineMode::Universal),
+        Some("") => Ok(NewlineMode::Untranslated),
+        Some("\n") => Ok(NewlineMode::Lf),
+        Some("\r") => Ok(NewlineMode::Cr),
+        Some("\r\n") => Ok(NewlineMode::CrLf),
+        Some(_) => Err(PyRuntimeError::new_err("Invalid newline")),
+    }
+}
+
+// Accepts either a format name or a path ending in the format's usual extension
+fn archive_format(name: &str) -> PyResult<ArchiveFormat> {
+    let name = name.to_lowercase();
+    if name.ends_with("tar.gz") || name.ends_with("tgz") {
+        Ok(ArchiveFormat::TarGz)
+    } else if name.ends_with("tar.zst") || name.ends_with("tzst") {
+        Ok(ArchiveFormat::TarZst)
+    } else if name.ends_with("tar") {
+        Ok(ArchiveFormat::Tar)
+    } else if name.ends_with("zip") {
+        Ok(ArchiveFormat::Zip)
+    } else {
+        Err(PyRuntimeError::new_err("Unknown archive format"))
+    }
+}
+
+// Uploads a file from the offset onwards, one chunk at a time. An empty file is still sent as one
+// empty chunk, so the agent creates it.
+fn send_chunks(
+    file: &File,
+    mut offset: u64,
+    size: u64,
+    chunk_size: u64,
+    mut upload: impl FnMut(FileChunk) -> PyResult<u64>,
+) -> PyResult<()> {
+    let mut data = vec![0u8; chunk_size.max(1) as usize];
+    loop {
+        let n = file.read_at(&mut data, offset)?;
+        if n == 0 && (offset > 0 || size > 0) {
+            return Ok(());
+        }
+        let chunk = FileChunk {
+            offset,
+            data: data[..n].to_vec(),
+        };
+        offset = upload(chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
 fn run_in_runtime<F, R>(client: &BhAgentClient, fut: F) -> PyResult<R>
 where
     F: Future<Output = Result<Result<R, AgentError>, RpcError>> + Sized,
@@ -32,6 +166,27 @@ where
         .and_then(|r| r)
 }
 
+fn process_channel(channel: i32) -> PyResult<ProcessChannel> {
+    match channel {
+        0 => Ok(ProcessChannel::Stdin),
+        1 => Ok(ProcessChannel::Stdout),
+        2 => Ok(ProcessChannel::Stderr),
+        _ => Err(PyRuntimeError::new_err("Invalid channel")),
+    }
+}
+
+// The default context gives up on a request after 10 seconds. Calls that block on the server for a
+// caller-provided timeout need a deadline past that timeout, and calls whose work grows with the
+// size of a file get a day unless the caller gives them a timeout.
+fn context_with_timeout(timeout: Option<f64>) -> Context {
+    let mut ctx = context::current();
+    let wait = timeout
+        .and_then(|t| Duration::try_from_secs_f64(t.max(0.0)).ok())
+        .unwrap_or(Duration::from_secs(24 * 60 * 60));
+    ctx.deadline = SystemTime::now() + wait + Duration::from_secs(10);
+    ctx
+}
+
 #[pymethods]
 impl BhAgentClient {
     #[staticmethod]
@@ -65,6 +220,29 @@ impl BhAgentClient {
         run_in_runtime(self, self.client.get_tempdir(context::current(), env_id))
     }
 
+    /// Creates a unique directory that is deleted when this client disconnects.
+    #[pyo3(signature = (env_id, prefix = "tmp", suffix = ""))]
+    fn make_temp_dir(&self, env_id: EnvironmentId, prefix: &str, suffix: &str) -> PyResult<String> {
+        self.make_temp(env_id, prefix, suffix, true)
+    }
+
+    /// Creates a unique empty file that is deleted when this client disconnects.
+    #[pyo3(signature = (env_id, prefix = "tmp", suffix = ""))]
+    fn make_temp_file(
+        &self,
+        env_id: EnvironmentId,
+        prefix: &str,
+        suffix: &str,
+    ) -> PyResult<String> {
+        self.make_temp(env_id, prefix, suffix, false)
+    }
+
+    #[pyo3(signature = (
+        env_id, argv, stdin, stdout, stderr, executable, env, cwd, setuid, setgid, setpgid,
+        detached = false, capture = false, capture_buffer_size = None, executable_image = None,
+        record = None, executable_blob = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn run_process(
         &self,
         env_id: EnvironmentId,
@@ -78,7 +256,18 @@ impl BhAgentClient {
         setuid: Option<u32>,
         setgid: Option<u32>,
         setpgid: bool,
+        detached: bool,
+        capture: bool,
+        capture_buffer_size: Option<u64>,
+        executable_image: Option<PyExecutableSource>,
+        record: Option<String>,
+        executable_blob: Option<String>,
     ) -> PyResult<ProcessId> {
+        // With capture set, stdout and stderr are drained by the agent instead of being piped
+        let output = match capture {
+            true => Redirection::Capture,
+            false => Redirection::Save,
+        };
         let config = RemotePOpenConfig {
             argv,
             stdin: match stdin {
@@ -86,11 +275,11 @@ impl BhAgentClient {
                 false => Redirection::None,
             },
             stdout: match stdout {
-                true => Redirection::Save,
+                true => output,
                 false => Redirection::None,
             },
             stderr: match stderr {
-                true => Redirection::Save,
+                true => output,
                 false => Redirection::None,
             },
             executable,
@@ -99,11 +288,117 @@ impl BhAgentClient {
             setuid,
             setgid,
             setpgid,
+            detached,
+            capture_buffer_size,
+            record,
+        };
+        // An executable image is run from memory on the agent, and a blob from the agent's blob
+        // store. Either one overrides the executable path.
+        match (executable_image, executable_blob) {
+            (Some(image), _) => run_in_runtime(
+                self,
+                self.client
+                    .run_memfd_command(context::current(), env_id, image.into(), config),
+            ),
+            (None, Some(hash)) => run_in_runtime(
+                self,
+                self.client
+                    .run_blob_command(context::current(), env_id, hash, config),
+            ),
+            (None, None) => run_in_runtime(
+                self,
+                self.client.run_command(context::current(), env_id, config),
+            ),
+        }
+    }
+
+    #[pyo3(signature = (
+        env_id, argv, input = Vec::new(), executable = None, env = None, cwd = None,
+        setuid = None, setgid = None, setpgid = false, timeout = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_and_collect(
+        &self,
+        env_id: EnvironmentId,
+        argv: Vec<String>,
+        input: Vec<u8>,
+        executable: Option<String>,
+        env: Option<Vec<(String, String)>>,
+        cwd: Option<String>,
+        setuid: Option<u32>,
+        setgid: Option<u32>,
+        setpgid: bool,
+        timeout: Option<f64>,
+    ) -> PyResult<PyCollectedOutput> {
+        let config = RemotePOpenConfig {
+            argv,
+            executable,
+            env,
+            cwd,
+            setuid,
+            setgid,
+            setpgid,
+            ..Default::default()
+        };
+        run_in_runtime(
+            self,
+            self.client.run_and_collect(
+                context_with_timeout(timeout),
+                env_id,
+                config,
+                input,
+                timeout,
+            ),
+        )
+        .map(Into::into)
+    }
+
+    #[pyo3(signature = (
+        env_id, transcript_path, argv, executable = None, env = None, cwd = None,
+        setuid = None, setgid = None, setpgid = false, timing = "compressed", timeout = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn replay_transcript(
+        &self,
+        env_id: EnvironmentId,
+        transcript_path: String,
+        argv: Vec<String>,
+        executable: Option<String>,
+        env: Option<Vec<(String, String)>>,
+        cwd: Option<String>,
+        setuid: Option<u32>,
+        setgid: Option<u32>,
+        setpgid: bool,
+        timing: &str,
+        timeout: Option<f64>,
+    ) -> PyResult<PyReplayReport> {
+        let timing = match timing {
+            "original" => ReplayTiming::Original,
+            "compressed" => ReplayTiming::Compressed,
+            _ => return Err(PyRuntimeError::new_err("Invalid replay timing")),
+        };
+        let config = RemotePOpenConfig {
+            argv,
+            executable,
+            env,
+            cwd,
+            setuid,
+            setgid,
+            setpgid,
+            ..Default::default()
         };
         run_in_runtime(
             self,
-            self.client.run_command(context::current(), env_id, config),
+            self.client.replay_transcript(
+                context_with_timeout(timeout),
+                env_id,
+                transcript_path,
+                config,
+                timing,
+                timeout,
+            ),
         )
+        .map(Into::into)
     }
 
     fn get_process_channel(
@@ -118,39 +413,139 @@ impl BhAgentClient {
                 context::current(),
                 env_id,
                 proc_id,
-                match channel {
-                    0 => ProcessChannel::Stdin,
-                    1 => ProcessChannel::Stdout,
-                    2 => ProcessChannel::Stderr,
-                    _ => return Err(PyRuntimeError::new_err("Invalid channel")),
-                },
+                process_channel(channel)?,
+            ),
+        )
+    }
+
+    fn process_kill(&self, env_id: EnvironmentId, proc_id: ProcessId) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .process_kill(context::current(), env_id, proc_id),
+        )
+    }
+
+    fn get_process_tree(
+        &self,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> PyResult<Vec<PyProcessTreeEntry>> {
+        run_in_runtime(
+            self,
+            self.client
+                .get_process_tree(context::current(), env_id, proc_id),
+        )
+        .map(|entries| entries.into_iter().map(Into::into).collect())
+    }
+
+    fn get_process_capture(
+        &self,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        start_seq: u64,
+    ) -> PyResult<PyCaptureTranscript> {
+        run_in_runtime(
+            self,
+            self.client
+                .get_process_capture(context::current(), env_id, proc_id, start_seq),
+        )
+        .map(Into::into)
+    }
+
+    fn process_expect(
+        &self,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        channel: i32,
+        pattern: PyMatchPattern,
+        timeout: Option<f64>,
+    ) -> PyResult<PyExpectMatch> {
+        run_in_runtime(
+            self,
+            self.client.process_expect(
+                context_with_timeout(timeout),
+                env_id,
+                proc_id,
+                process_channel(channel)?,
+                pattern.into(),
+                timeout,
             ),
         )
+        .map(Into::into)
+    }
+
+    fn process_sendline(
+        &self,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        data: Vec<u8>,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .process_sendline(context::current(), env_id, proc_id, data),
+        )
+    }
+
+    fn list_detached_processes(
+        &self,
+        env_id: EnvironmentId,
+    ) -> PyResult<Vec<PyDetachedProcessInfo>> {
+        run_in_runtime(
+            self,
+            self.client
+                .list_detached_processes(context::current(), env_id),
+        )
+        .map(|infos| infos.into_iter().map(Into::into).collect())
+    }
+
+    fn attach_process(&self, env_id: EnvironmentId, proc_id: ProcessId) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .attach_process(context::current(), env_id, proc_id),
+        )
+    }
+
+    fn forget_process(&self, env_id: EnvironmentId, proc_id: ProcessId) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .forget_process(context::current(), env_id, proc_id),
+        )
     }
 
     // File IO
+    /// Opens a file with Python's open() mode strings and text arguments. Text files use UTF-8,
+    /// strict errors and universal newlines unless told otherwise.
+    #[pyo3(signature = (
+        env_id, path, mode_and_type, encoding = None, errors = None, newline = None
+    ))]
     fn file_open(
         &self,
         env_id: EnvironmentId,
         path: String,
         mode_and_type: String,
+        encoding: Option<&str>,
+        errors: Option<&str>,
+        newline: Option<&str>,
     ) -> PyResult<FileId> {
-        // Mode parsing
-        let mut mode = FileOpenMode::Read;
-        mode_and_type.chars().for_each(|c| match c {
-            'r' => mode = FileOpenMode::Read,
-            'w' => mode = FileOpenMode::Write,
-            'x' => mode = FileOpenMode::ExclusiveWrite,
-            'a' => mode = FileOpenMode::Append,
-            '+' => mode = FileOpenMode::Update,
-            _ => {}
-        });
-
-        // Type parsing
-        let mut type_ = FileOpenType::Text;
-        if mode_and_type.contains("b") {
-            type_ = FileOpenType::Binary;
-        }
+        let (mode, binary) = open_mode(&mode_and_type)?;
+        let type_ = if binary {
+            if encoding.is_some() || errors.is_some() || newline.is_some() {
+                return Err(PyRuntimeError::new_err(
+                    "Binary mode doesn't take an encoding, errors or newline argument",
+                ));
+            }
+            FileOpenType::Binary
+        } else {
+            FileOpenType::Text {
+                encoding: text_encoding(encoding.unwrap_or("utf-8"))?,
+                errors: text_errors(errors.unwrap_or("strict"))?,
+                newline: newline_mode(newline)?,
+            }
+        };
 
         run_in_runtime(
             self,
@@ -185,12 +580,34 @@ impl BhAgentClient {
         )
     }
 
+    /// Reads a whole line, or at most limit characters of it. A negative limit is no limit.
+    #[pyo3(signature = (env_id, fd, limit = None))]
+    fn file_readline(
+        &self,
+        env_id: EnvironmentId,
+        fd: FileId,
+        limit: Option<i64>,
+    ) -> PyResult<Vec<u8>> {
+        let limit = limit
+            .filter(|limit| *limit >= 0)
+            .map(|limit| u32::try_from(limit).unwrap_or(u32::MAX));
+        run_in_runtime(
+            self,
+            self.client
+                .file_readline(context::current(), env_id, fd, limit),
+        )
+    }
+
+    /// Reads lines until they add up to at least hint characters. A hint of 0 or less reads
+    /// every remaining line.
+    #[pyo3(signature = (env_id, fd, hint = -1))]
     fn file_read_lines(
         &self,
         env_id: EnvironmentId,
         fd: FileId,
-        hint: u32,
+        hint: i64,
     ) -> PyResult<Vec<Vec<u8>>> {
+        let hint = u32::try_from(hint.max(0)).unwrap_or(u32::MAX);
         run_in_runtime(
             self,
             self.client
@@ -205,13 +622,14 @@ impl BhAgentClient {
         )
     }
 
+    #[pyo3(signature = (env_id, fd, offset, whence = 0))]
     fn file_seek(
         &self,
         env_id: EnvironmentId,
         fd: FileId,
-        offset: i32,
+        offset: i64,
         whence: i32,
-    ) -> PyResult<()> {
+    ) -> PyResult<u64> {
         run_in_runtime(
             self,
             self.client
@@ -219,10 +637,19 @@ impl BhAgentClient {
         )
     }
 
-    fn file_tell(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<i32> {
+    fn file_tell(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<u64> {
         run_in_runtime(self, self.client.file_tell(context::current(), env_id, fd))
     }
 
+    #[pyo3(signature = (env_id, fd, size = None))]
+    fn file_truncate(&self, env_id: EnvironmentId, fd: FileId, size: Option<u64>) -> PyResult<u64> {
+        run_in_runtime(
+            self,
+            self.client
+                .file_truncate(context::current(), env_id, fd, size),
+        )
+    }
+
     fn file_is_writable(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<bool> {
         run_in_runtime(
             self,
@@ -236,10 +663,796 @@ impl BhAgentClient {
             self.client.file_write(context::current(), env_id, fd, data),
         )
     }
-}
 
-#[pymodule]
-pub fn bh_agent_client(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<BhAgentClient>()?;
-    Ok(())
+    fn file_pread(
+        &self,
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        num_bytes: u32,
+    ) -> PyResult<Vec<u8>> {
+        run_in_runtime(
+            self,
+            self.client
+                .file_pread(context::current(), env_id, fd, offset, num_bytes),
+        )
+    }
+
+    fn file_pwrite(
+        &self,
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .file_pwrite(context::current(), env_id, fd, offset, data),
+        )
+    }
+
+    /// Takes a list of (offset, num_bytes) pairs and returns the data for each of them.
+    fn file_pread_many(
+        &self,
+        env_id: EnvironmentId,
+        fd: FileId,
+        ranges: Vec<(u64, u32)>,
+    ) -> PyResult<Vec<Vec<u8>>> {
+        run_in_runtime(
+            self,
+            self.client
+                .file_pread_many(context::current(), env_id, fd, ranges),
+        )
+    }
+
+    fn file_flush(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<()> {
+        run_in_runtime(self, self.client.file_flush(context::current(), env_id, fd))
+    }
+
+    #[pyo3(signature = (env_id, fd, data_only = false))]
+    fn file_fsync(&self, env_id: EnvironmentId, fd: FileId, data_only: bool) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .file_fsync(context::current(), env_id, fd, data_only),
+        )
+    }
+
+    /// Takes a "shared" or "exclusive" advisory lock on the file.
+    #[pyo3(signature = (env_id, fd, kind = "exclusive", blocking = true, timeout = None))]
+    fn file_lock(
+        &self,
+        env_id: EnvironmentId,
+        fd: FileId,
+        kind: &str,
+        blocking: bool,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let kind = match kind {
+            "shared" => LockKind::Shared,
+            "exclusive" => LockKind::Exclusive,
+            _ => return Err(PyRuntimeError::new_err("Invalid lock kind")),
+        };
+        let ctx = match blocking {
+            true => context_with_timeout(timeout),
+            false => context::current(),
+        };
+        run_in_runtime(
+            self,
+            self.client
+                .file_lock(ctx, env_id, fd, kind, blocking, timeout),
+        )
+    }
+
+    fn file_unlock(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.file_unlock(context::current(), env_id, fd),
+        )
+    }
+
+    fn path_stat(&self, env_id: EnvironmentId, path: String) -> PyResult<PyFileStat> {
+        run_in_runtime(
+            self,
+            self.client.path_stat(context::current(), env_id, path),
+        )
+        .map(Into::into)
+    }
+
+    fn path_lstat(&self, env_id: EnvironmentId, path: String) -> PyResult<PyFileStat> {
+        run_in_runtime(
+            self,
+            self.client.path_lstat(context::current(), env_id, path),
+        )
+        .map(Into::into)
+    }
+
+    fn fd_stat(&self, env_id: EnvironmentId, fd: FileId) -> PyResult<PyFileStat> {
+        run_in_runtime(self, self.client.fd_stat(context::current(), env_id, fd)).map(Into::into)
+    }
+
+    #[pyo3(signature = (env_id, path, recursive = false, pattern = None, with_stat = false))]
+    fn list_dir(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        pattern: Option<String>,
+        with_stat: bool,
+    ) -> PyResult<Vec<PyDirEntry>> {
+        run_in_runtime(
+            self,
+            self.client.list_dir(
+                context::current(),
+                env_id,
+                path,
+                recursive,
+                pattern,
+                with_stat,
+            ),
+        )
+        .map(|entries| entries.into_iter().map(Into::into).collect())
+    }
+
+    #[pyo3(signature = (env_id, path, mode = None, exist_ok = false))]
+    fn make_dirs(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        mode: Option<u32>,
+        exist_ok: bool,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .make_dirs(context::current(), env_id, path, mode, exist_ok),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, recursive = false))]
+    fn remove(&self, env_id: EnvironmentId, path: String, recursive: bool) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .remove(context::current(), env_id, path, recursive),
+        )
+    }
+
+    fn rename(&self, env_id: EnvironmentId, src: String, dst: String) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.rename(context::current(), env_id, src, dst),
+        )
+    }
+
+    fn chmod(&self, env_id: EnvironmentId, path: String, mode: u32) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.chmod(context::current(), env_id, path, mode),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, uid = None, gid = None, follow_symlinks = true))]
+    fn chown(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        follow_symlinks: bool,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .chown(context::current(), env_id, path, uid, gid, follow_symlinks),
+        )
+    }
+
+    fn symlink(&self, env_id: EnvironmentId, target: String, link_path: String) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .symlink(context::current(), env_id, target, link_path),
+        )
+    }
+
+    fn hardlink(&self, env_id: EnvironmentId, target: String, link_path: String) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .hardlink(context::current(), env_id, target, link_path),
+        )
+    }
+
+    fn readlink(&self, env_id: EnvironmentId, path: String) -> PyResult<String> {
+        run_in_runtime(self, self.client.readlink(context::current(), env_id, path))
+    }
+
+    #[pyo3(signature = (env_id, path, atime_ns = None, mtime_ns = None, follow_symlinks = true))]
+    fn utime(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        atime_ns: Option<i64>,
+        mtime_ns: Option<i64>,
+        follow_symlinks: bool,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.utime(
+                context::current(),
+                env_id,
+                path,
+                atime_ns,
+                mtime_ns,
+                follow_symlinks,
+            ),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, follow_symlinks = true))]
+    fn list_xattrs(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        follow_symlinks: bool,
+    ) -> PyResult<Vec<String>> {
+        run_in_runtime(
+            self,
+            self.client
+                .list_xattrs(context::current(), env_id, path, follow_symlinks),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, name, follow_symlinks = true))]
+    fn get_xattr(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> PyResult<Vec<u8>> {
+        run_in_runtime(
+            self,
+            self.client
+                .get_xattr(context::current(), env_id, path, name, follow_symlinks),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, name, value, follow_symlinks = true))]
+    fn set_xattr(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        value: Vec<u8>,
+        follow_symlinks: bool,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.set_xattr(
+                context::current(),
+                env_id,
+                path,
+                name,
+                value,
+                follow_symlinks,
+            ),
+        )
+    }
+
+    #[pyo3(signature = (env_id, path, name, follow_symlinks = true))]
+    fn remove_xattr(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .remove_xattr(context::current(), env_id, path, name, follow_symlinks),
+        )
+    }
+
+    /// Returns the file capabilities of a path by name, or None if it has none.
+    fn get_capabilities(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+    ) -> PyResult<Option<PyFileCapabilities>> {
+        run_in_runtime(
+            self,
+            self.client
+                .get_capabilities(context::current(), env_id, path),
+        )
+        .map(|caps| caps.map(Into::into))
+    }
+
+    /// Sets file capabilities, given as names like "cap_net_raw".
+    #[pyo3(signature = (
+        env_id, path, permitted, inheritable = vec![], effective = true, rootid = None
+    ))]
+    fn set_capabilities(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        permitted: Vec<String>,
+        inheritable: Vec<String>,
+        effective: bool,
+        rootid: Option<u32>,
+    ) -> PyResult<()> {
+        let caps = FileCapabilities {
+            permitted: capability_set(&permitted)?,
+            inheritable: capability_set(&inheritable)?,
+            effective,
+            rootid,
+        };
+        run_in_runtime(
+            self,
+            self.client
+                .set_capabilities(context::current(), env_id, path, Some(caps)),
+        )
+    }
+
+    fn remove_capabilities(&self, env_id: EnvironmentId, path: String) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .set_capabilities(context::current(), env_id, path, None),
+        )
+    }
+
+    /// Uploads a local file to the agent. An upload that was interrupted resumes where it left off,
+    /// and an upload that fails verification is restarted once from the beginning. The timeout
+    /// applies to verifying the uploaded file.
+    #[pyo3(signature = (env_id, local, remote, chunk_size = DEFAULT_CHUNK_SIZE, timeout = None))]
+    fn put(
+        &self,
+        env_id: EnvironmentId,
+        local: String,
+        remote: String,
+        chunk_size: u64,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let mut file = File::open(&local)?;
+        let metadata = file.metadata()?;
+        let info = FileTransferInfo {
+            size: metadata.len(),
+            mode: metadata.mode() & 0o7777,
+            mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            sha256: sha256_file(&mut file)?,
+        };
+
+        let mut retried = false;
+        loop {
+            let mut offset = run_in_runtime(
+                self,
+                self.client.upload_file_status(
+                    context::current(),
+                    env_id,
+                    remote.clone(),
+                    info.sha256.clone(),
+                ),
+            )?;
+            // A partial file bigger than the source can't be from this file
+            if offset > info.size {
+                offset = 0;
+            }
+            send_chunks(&file, offset, info.size, chunk_size, |chunk| {
+                run_in_runtime(
+                    self,
+                    self.client.upload_file(
+                        context::current(),
+                        env_id,
+                        remote.clone(),
+                        info.sha256.clone(),
+                        chunk,
+                    ),
+                )
+            })?;
+
+            let result = self.tokio_runtime.block_on(self.client.upload_file_finish(
+                context_with_timeout(timeout),
+                env_id,
+                remote.clone(),
+                info.clone(),
+            ));
+            match result {
+                Ok(Err(AgentError::HashMismatch)) if !retried => retried = true,
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => return Err(PyRuntimeError::new_err(e.to_string())),
+                Err(e) => return Err(PyRuntimeError::new_err(e.to_string())),
+            }
+        }
+    }
+
+    /// Downloads a file from the agent. A download that was interrupted resumes where it left off,
+    /// and a download that fails verification is restarted once from the beginning. The timeout
+    /// applies to hashing the file on the agent.
+    #[pyo3(signature = (env_id, remote, local, chunk_size = DEFAULT_CHUNK_SIZE, timeout = None))]
+    fn get(
+        &self,
+        env_id: EnvironmentId,
+        remote: String,
+        local: String,
+        chunk_size: u64,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let info = run_in_runtime(
+            self,
+            self.client
+                .download_file_info(context_with_timeout(timeout), env_id, remote.clone()),
+        )?;
+        let partial = format!("{}.bh_partial", local);
+
+        for attempt in 0..2 {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(attempt > 0)
+                .open(&partial)?;
+            let mut offset = file.metadata()?.len();
+            if offset > info.size {
+                offset = 0;
+            }
+            file.set_len(offset)?;
+            while offset < info.size {
+                let chunk = run_in_runtime(
+                    self,
+                    self.client.download_file(
+                        context::current(),
+                        env_id,
+                        remote.clone(),
+                        offset,
+                        chunk_size,
+                    ),
+                )?;
+                // The file shrank on the agent since the transfer started
+                if chunk.data.is_empty() {
+                    break;
+                }
+                file.write_all_at(&chunk.data, offset)?;
+                offset += chunk.data.len() as u64;
+            }
+
+            file.seek(SeekFrom::Start(0))?;
+            if offset == info.size && sha256_file(&mut file)? == info.sha256 {
+                file.set_permissions(Permissions::from_mode(info.mode))?;
+                let mtime = UNIX_EPOCH + Duration::from_nanos(info.mtime_ns.max(0) as u64);
+                file.set_times(FileTimes::new().set_modified(mtime))?;
+                drop(file);
+                std::fs::rename(&partial, &local)?;
+                return Ok(());
+            }
+        }
+        std::fs::remove_file(&partial)?;
+        Err(PyRuntimeError::new_err(
+            AgentError::HashMismatch.to_string(),
+        ))
+    }
+
+    #[pyo3(signature = (env_id, archive_path, dest_dir, format = None, timeout = None))]
+    fn extract_archive(
+        &self,
+        env_id: EnvironmentId,
+        archive_path: String,
+        dest_dir: String,
+        format: Option<&str>,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let format = format.map(archive_format).transpose()?;
+        run_in_runtime(
+            self,
+            self.client.extract_archive(
+                context_with_timeout(timeout),
+                env_id,
+                archive_path,
+                dest_dir,
+                format,
+            ),
+        )
+    }
+
+    #[pyo3(signature = (env_id, src_dir, archive_path, format = None, timeout = None))]
+    fn pack_archive(
+        &self,
+        env_id: EnvironmentId,
+        src_dir: String,
+        archive_path: String,
+        format: Option<&str>,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let format = archive_format(format.unwrap_or(&archive_path))?;
+        run_in_runtime(
+            self,
+            self.client.pack_archive(
+                context_with_timeout(timeout),
+                env_id,
+                src_dir,
+                archive_path,
+                format,
+            ),
+        )
+    }
+
+    #[pyo3(signature = (
+        env_id, path_or_fd, algorithm = "sha256", offset = 0, length = None, timeout = None
+    ))]
+    fn file_hash(
+        &self,
+        env_id: EnvironmentId,
+        path_or_fd: PyFileRef,
+        algorithm: &str,
+        offset: u64,
+        length: Option<u64>,
+        timeout: Option<f64>,
+    ) -> PyResult<String> {
+        let algorithm = match algorithm.to_lowercase().as_str() {
+            "sha256" => HashAlgorithm::Sha256,
+            "sha1" => HashAlgorithm::Sha1,
+            "md5" => HashAlgorithm::Md5,
+            "blake3" => HashAlgorithm::Blake3,
+            _ => return Err(PyRuntimeError::new_err("Unsupported hash algorithm")),
+        };
+        let range = (offset != 0 || length.is_some()).then_some(ByteRange { offset, length });
+        run_in_runtime(
+            self,
+            self.client.file_hash(
+                context_with_timeout(timeout),
+                env_id,
+                path_or_fd.into(),
+                algorithm,
+                range,
+            ),
+        )
+    }
+
+    /// Searches a file or directory tree on the agent. A str pattern is a regex, a bytes pattern
+    /// is matched exactly.
+    #[pyo3(signature = (
+        env_id, path, pattern, context = 0, max_matches = None, max_bytes = None, timeout = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn search(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        pattern: PyMatchPattern,
+        context: u32,
+        max_matches: Option<u32>,
+        max_bytes: Option<u64>,
+        timeout: Option<f64>,
+    ) -> PyResult<PySearchResult> {
+        run_in_runtime(
+            self,
+            self.client.search(
+                context_with_timeout(timeout),
+                env_id,
+                path,
+                pattern.into(),
+                context,
+                SearchLimits {
+                    max_matches,
+                    max_bytes,
+                },
+            ),
+        )
+        .map(Into::into)
+    }
+
+    fn blob_missing(&self, env_id: EnvironmentId, hashes: Vec<String>) -> PyResult<Vec<String>> {
+        run_in_runtime(
+            self,
+            self.client.blob_missing(context::current(), env_id, hashes),
+        )
+    }
+
+    /// Adds a local file to the agent's blob store, skipping the upload if the agent already has
+    /// it. Returns the hash the blob is stored under.
+    #[pyo3(signature = (env_id, local, chunk_size = DEFAULT_CHUNK_SIZE))]
+    fn blob_put(&self, env_id: EnvironmentId, local: String, chunk_size: u64) -> PyResult<String> {
+        let mut file = File::open(&local)?;
+        let size = file.metadata()?.len();
+        let hash = sha256_file(&mut file)?;
+        let missing = self.blob_missing(env_id, vec![hash.clone()])?;
+        if !missing.is_empty() {
+            send_chunks(&file, 0, size, chunk_size, |chunk| {
+                run_in_runtime(
+                    self,
+                    self.client
+                        .blob_upload(context::current(), env_id, hash.clone(), chunk),
+                )
+            })?;
+            run_in_runtime(
+                self,
+                self.client
+                    .blob_upload_finish(context::current(), env_id, hash.clone(), size),
+            )?;
+        }
+        Ok(hash)
+    }
+
+    #[pyo3(signature = (env_id, hash, path, hardlink = true, mode = None))]
+    fn blob_materialize(
+        &self,
+        env_id: EnvironmentId,
+        hash: String,
+        path: String,
+        hardlink: bool,
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client
+                .blob_materialize(context::current(), env_id, hash, path, hardlink, mode),
+        )
+    }
+
+    /// Starts watching a path. Events are given by name: create, modify, delete, moved_from,
+    /// moved_to and close_write. All of them are watched by default.
+    #[pyo3(signature = (env_id, path, recursive = false, events = None))]
+    fn watch_path(
+        &self,
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        events: Option<Vec<String>>,
+    ) -> PyResult<WatchId> {
+        let events = events
+            .unwrap_or_default()
+            .iter()
+            .map(|name| watch_event_kind(name))
+            .collect::<PyResult<Vec<_>>>()?;
+        run_in_runtime(
+            self,
+            self.client
+                .watch_path(context::current(), env_id, path, recursive, events),
+        )
+    }
+
+    #[pyo3(signature = (env_id, watch_id, max_events = 1024, timeout = None))]
+    fn watch_events(
+        &self,
+        env_id: EnvironmentId,
+        watch_id: WatchId,
+        max_events: u32,
+        timeout: Option<f64>,
+    ) -> PyResult<Vec<PyWatchEvent>> {
+        run_in_runtime(
+            self,
+            self.client.watch_events(
+                context_with_timeout(timeout),
+                env_id,
+                watch_id,
+                max_events,
+                timeout,
+            ),
+        )
+        .map(|events| events.into_iter().map(Into::into).collect())
+    }
+
+    fn unwatch(&self, env_id: EnvironmentId, watch_id: WatchId) -> PyResult<()> {
+        run_in_runtime(
+            self,
+            self.client.unwatch(context::current(), env_id, watch_id),
+        )
+    }
+
+    /// Uploads a local archive and extracts it into a directory on the agent.
+    #[pyo3(signature = (env_id, local, remote_dir, format = None))]
+    fn put_archive(
+        &self,
+        env_id: EnvironmentId,
+        local: String,
+        remote_dir: String,
+        format: Option<&str>,
+    ) -> PyResult<()> {
+        let remote = self.remote_temp_path(env_id)?;
+        let result = self
+            .put(env_id, local, remote.clone(), DEFAULT_CHUNK_SIZE, None)
+            .and_then(|_| self.extract_archive(env_id, remote.clone(), remote_dir, format, None));
+        let _ = self.remove(env_id, remote, false);
+        result
+    }
+
+    /// Packs a directory on the agent into a local archive. Unless it is given, the format is
+    /// picked from the extension of the local path.
+    #[pyo3(signature = (env_id, remote_dir, local, format = None))]
+    fn get_archive(
+        &self,
+        env_id: EnvironmentId,
+        remote_dir: String,
+        local: String,
+        format: Option<&str>,
+    ) -> PyResult<()> {
+        let format = format.unwrap_or(&local).to_string();
+        let remote = self.remote_temp_path(env_id)?;
+        let result = self
+            .pack_archive(env_id, remote_dir, remote.clone(), Some(&format), None)
+            .and_then(|_| self.get(env_id, remote.clone(), local, DEFAULT_CHUNK_SIZE, None));
+        let _ = self.remove(env_id, remote, false);
+        result
+    }
+}
+
+impl BhAgentClient {
+    fn make_temp(
+        &self,
+        env_id: EnvironmentId,
+        prefix: &str,
+        suffix: &str,
+        directory: bool,
+    ) -> PyResult<String> {
+        run_in_runtime(
+            self,
+            self.client.make_temp(
+                context::current(),
+                env_id,
+                prefix.to_string(),
+                suffix.to_string(),
+                directory,
+            ),
+        )
+    }
+
+    fn remote_temp_path(&self, env_id: EnvironmentId) -> PyResult<String> {
+        self.make_temp(env_id, "bh_archive_", "", false)
+    }
+}
+
+#[pymodule]
+pub fn bh_agent_client(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<BhAgentClient>()?;
+    m.add_class::<PyDetachedProcessInfo>()?;
+    m.add_class::<PyExpectMatch>()?;
+    m.add_class::<PyOutputChunk>()?;
+    m.add_class::<PyCaptureTranscript>()?;
+    m.add_class::<PyCollectedOutput>()?;
+    m.add_class::<PyProcessTreeEntry>()?;
+    m.add_class::<PyReplayDivergence>()?;
+    m.add_class::<PyReplayReport>()?;
+    m.add_class::<PyFileStat>()?;
+    m.add_class::<PyDirEntry>()?;
+    m.add_class::<PySearchMatch>()?;
+    m.add_class::<PySearchResult>()?;
+    m.add_class::<PyWatchEvent>()?;
+    m.add_class::<PyFileCapabilities>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_mode() {
+        let valid = [
+            ("r", FileOpenMode::Read),
+            ("r+", FileOpenMode::ReadUpdate),
+            ("w", FileOpenMode::Write),
+            ("w+", FileOpenMode::WriteUpdate),
+            ("x", FileOpenMode::ExclusiveWrite),
+            ("x+", FileOpenMode::ExclusiveWriteUpdate),
+            ("a", FileOpenMode::Append),
+            ("a+", FileOpenMode::AppendUpdate),
+        ];
+        for (mode, expected) in valid {
+            assert_eq!(open_mode(mode).unwrap(), (expected, false));
+            assert_eq!(open_mode(&format!("{}t", mode)).unwrap(), (expected, false));
+            assert_eq!(open_mode(&format!("{}b", mode)).unwrap(), (expected, true));
+            // Like Python, the order of the characters doesn't matter
+            let reversed: String = format!("{}b", mode).chars().rev().collect();
+            assert_eq!(open_mode(&reversed).unwrap(), (expected, true));
+        }
+
+        for mode in [
+            "", "rw", "xa", "r+w", "rbb", "r++", "bt", "rbt", "b", "+", "z", "rU",
+        ] {
+            assert!(open_mode(mode).is_err(), "{:?} should be invalid", mode);
+        }
+    }
 }
@@ -1,4 +1,5 @@
 mod bindings;
 mod client;
+mod types;
 
 pub use bindings::bh_agent_client;
@@ -0,0 +1,467 @@
+use bh_agent_common::{
+    ExecutableSource, ExitStatus, FileId, FileRef, FileType, MatchPattern, ProcessChannel,
+    ProcessId, WatchEventKind,
+};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::{pyclass, FromPyObject, PyResult};
+
+#[pyclass(name = "DetachedProcessInfo", get_all)]
+pub struct PyDetachedProcessInfo {
+    proc_id: ProcessId,
+    pid: Option<u32>,
+    argv: Vec<String>,
+    owner: String,
+    running: bool,
+}
+
+impl From<bh_agent_common::DetachedProcessInfo> for PyDetachedProcessInfo {
+    fn from(info: bh_agent_common::DetachedProcessInfo) -> Self {
+        Self {
+            proc_id: info.proc_id,
+            pid: info.pid,
+            argv: info.argv,
+            owner: info.owner,
+            running: info.running,
+        }
+    }
+}
+
+/// Either the bytes of an executable, or a file ID to read them from
+#[derive(FromPyObject)]
+pub enum PyExecutableSource {
+    Bytes(Vec<u8>),
+    File(FileId),
+}
+
+impl From<PyExecutableSource> for ExecutableSource {
+    fn from(source: PyExecutableSource) -> Self {
+        match source {
+            PyExecutableSource::Bytes(b) => ExecutableSource::Bytes(b),
+            PyExecutableSource::File(fd) => ExecutableSource::File(fd),
+        }
+    }
+}
+
+/// A str is a path, an int is a file ID
+#[derive(FromPyObject)]
+pub enum PyFileRef {
+    Path(String),
+    File(FileId),
+}
+
+impl From<PyFileRef> for FileRef {
+    fn from(file: PyFileRef) -> Self {
+        match file {
+            PyFileRef::Path(path) => FileRef::Path(path),
+            PyFileRef::File(fd) => FileRef::File(fd),
+        }
+    }
+}
+
+/// A str pattern is a regex, a bytes pattern is matched exactly.
+#[derive(FromPyObject)]
+pub enum PyMatchPattern {
+    Regex(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<PyMatchPattern> for MatchPattern {
+    fn from(pattern: PyMatchPattern) -> Self {
+        match pattern {
+            PyMatchPattern::Regex(re) => MatchPattern::Regex(re),
+            PyMatchPattern::Bytes(b) => MatchPattern::Bytes(b),
+        }
+    }
+}
+
+#[pyclass(name = "ExpectMatch", get_all)]
+pub struct PyExpectMatch {
+    before: Vec<u8>,
+    matched: Vec<u8>,
+    captures: Vec<Option<Vec<u8>>>,
+}
+
+impl From<bh_agent_common::ExpectMatch> for PyExpectMatch {
+    fn from(m: bh_agent_common::ExpectMatch) -> Self {
+        Self {
+            before: m.before,
+            matched: m.matched,
+            captures: m.captures,
+        }
+    }
+}
+
+// Channels are exposed to Python as 0, 1 and 2, matching get_process_channel
+fn channel_number(channel: ProcessChannel) -> i32 {
+    match channel {
+        ProcessChannel::Stdin => 0,
+        ProcessChannel::Stdout => 1,
+        ProcessChannel::Stderr => 2,
+    }
+}
+
+#[pyclass(name = "OutputChunk", get_all)]
+#[derive(Clone)]
+pub struct PyOutputChunk {
+    seq: u64,
+    channel: i32,
+    timestamp_ns: u64,
+    data: Vec<u8>,
+}
+
+impl From<bh_agent_common::OutputChunk> for PyOutputChunk {
+    fn from(chunk: bh_agent_common::OutputChunk) -> Self {
+        Self {
+            seq: chunk.seq,
+            channel: channel_number(chunk.channel),
+            timestamp_ns: chunk.timestamp_ns,
+            data: chunk.data,
+        }
+    }
+}
+
+#[pyclass(name = "CaptureTranscript", get_all)]
+pub struct PyCaptureTranscript {
+    chunks: Vec<PyOutputChunk>,
+    dropped_bytes: u64,
+    complete: bool,
+}
+
+impl From<bh_agent_common::CaptureTranscript> for PyCaptureTranscript {
+    fn from(transcript: bh_agent_common::CaptureTranscript) -> Self {
+        Self {
+            chunks: transcript.chunks.into_iter().map(Into::into).collect(),
+            dropped_bytes: transcript.dropped_bytes,
+            complete: transcript.complete,
+        }
+    }
+}
+
+// Follows subprocess.Popen.returncode: negative for a process killed by a signal
+fn returncode(status: ExitStatus) -> Option<i64> {
+    match status {
+        ExitStatus::Exited(code) => Some(code as i64),
+        ExitStatus::Signaled(signal) => Some(-(signal as i64)),
+        ExitStatus::Other(_) | ExitStatus::Undetermined => None,
+    }
+}
+
+#[pyclass(name = "CollectedOutput", get_all)]
+pub struct PyCollectedOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    returncode: Option<i64>,
+    timed_out: bool,
+}
+
+impl From<bh_agent_common::CollectedOutput> for PyCollectedOutput {
+    fn from(output: bh_agent_common::CollectedOutput) -> Self {
+        Self {
+            stdout: output.stdout,
+            stderr: output.stderr,
+            returncode: returncode(output.exit_status),
+            timed_out: output.timed_out,
+        }
+    }
+}
+
+#[pyclass(name = "ProcessTreeEntry", get_all)]
+pub struct PyProcessTreeEntry {
+    pid: u32,
+    ppid: u32,
+    name: String,
+}
+
+impl From<bh_agent_common::ProcessTreeEntry> for PyProcessTreeEntry {
+    fn from(entry: bh_agent_common::ProcessTreeEntry) -> Self {
+        Self {
+            pid: entry.pid,
+            ppid: entry.ppid,
+            name: entry.name,
+        }
+    }
+}
+
+#[pyclass(name = "ReplayDivergence", get_all)]
+#[derive(Clone)]
+pub struct PyReplayDivergence {
+    channel: i32,
+    offset: u64,
+    expected: Vec<u8>,
+    actual: Vec<u8>,
+}
+
+impl From<bh_agent_common::ReplayDivergence> for PyReplayDivergence {
+    fn from(divergence: bh_agent_common::ReplayDivergence) -> Self {
+        Self {
+            channel: channel_number(divergence.channel),
+            offset: divergence.offset,
+            expected: divergence.expected,
+            actual: divergence.actual,
+        }
+    }
+}
+
+#[pyclass(name = "ReplayReport", get_all)]
+pub struct PyReplayReport {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    returncode: Option<i64>,
+    timed_out: bool,
+    divergences: Vec<PyReplayDivergence>,
+}
+
+impl From<bh_agent_common::ReplayReport> for PyReplayReport {
+    fn from(report: bh_agent_common::ReplayReport) -> Self {
+        Self {
+            stdout: report.stdout,
+            stderr: report.stderr,
+            returncode: returncode(report.exit_status),
+            timed_out: report.timed_out,
+            divergences: report.divergences.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+fn file_type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symlink",
+        FileType::BlockDevice => "block_device",
+        FileType::CharDevice => "char_device",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+        FileType::Unknown => "unknown",
+    }
+}
+
+#[pyclass(name = "FileStat", get_all)]
+#[derive(Clone)]
+pub struct PyFileStat {
+    file_type: &'static str,
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    dev: u64,
+    inode: u64,
+    nlink: u64,
+    atime_ns: i64,
+    mtime_ns: i64,
+    ctime_ns: i64,
+}
+
+impl From<bh_agent_common::FileStat> for PyFileStat {
+    fn from(stat: bh_agent_common::FileStat) -> Self {
+        Self {
+            file_type: file_type_name(stat.file_type),
+            size: stat.size,
+            mode: stat.mode,
+            uid: stat.uid,
+            gid: stat.gid,
+            dev: stat.dev,
+            inode: stat.inode,
+            nlink: stat.nlink,
+            atime_ns: stat.atime_ns,
+            mtime_ns: stat.mtime_ns,
+            ctime_ns: stat.ctime_ns,
+        }
+    }
+}
+
+#[pyclass(name = "DirEntry", get_all)]
+pub struct PyDirEntry {
+    path: String,
+    file_type: &'static str,
+    stat: Option<PyFileStat>,
+}
+
+impl From<bh_agent_common::DirEntry> for PyDirEntry {
+    fn from(entry: bh_agent_common::DirEntry) -> Self {
+        Self {
+            path: entry.path,
+            file_type: file_type_name(entry.file_type),
+            stat: entry.stat.map(Into::into),
+        }
+    }
+}
+
+#[pyclass(name = "SearchMatch", get_all)]
+#[derive(Clone)]
+pub struct PySearchMatch {
+    path: String,
+    offset: u64,
+    line: u64,
+    matched: Vec<u8>,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+impl From<bh_agent_common::SearchMatch> for PySearchMatch {
+    fn from(m: bh_agent_common::SearchMatch) -> Self {
+        Self {
+            path: m.path,
+            offset: m.offset,
+            line: m.line,
+            matched: m.matched,
+            before: m.before,
+            after: m.after,
+        }
+    }
+}
+
+#[pyclass(name = "SearchResult", get_all)]
+pub struct PySearchResult {
+    matches: Vec<PySearchMatch>,
+    bytes_scanned: u64,
+    truncated: bool,
+}
+
+impl From<bh_agent_common::SearchResult> for PySearchResult {
+    fn from(result: bh_agent_common::SearchResult) -> Self {
+        Self {
+            matches: result.matches.into_iter().map(Into::into).collect(),
+            bytes_scanned: result.bytes_scanned,
+            truncated: result.truncated,
+        }
+    }
+}
+
+fn watch_event_kind_name(kind: WatchEventKind) -> &'static str {
+    match kind {
+        WatchEventKind::Create => "create",
+        WatchEventKind::Modify => "modify",
+        WatchEventKind::Delete => "delete",
+        WatchEventKind::MovedFrom => "moved_from",
+        WatchEventKind::MovedTo => "moved_to",
+        WatchEventKind::CloseWrite => "close_write",
+        WatchEventKind::Overflow => "overflow",
+    }
+}
+
+pub fn watch_event_kind(name: &str) -> PyResult<WatchEventKind> {
+    match name {
+        "create" => Ok(WatchEventKind::Create),
+        "modify" => Ok(WatchEventKind::Modify),
+        "delete" => Ok(WatchEventKind::Delete),
+        "moved_from" => Ok(WatchEventKind::MovedFrom),
+        "moved_to" => Ok(WatchEventKind::MovedTo),
+        "close_write" => Ok(WatchEventKind::CloseWrite),
+        _ => Err(PyRuntimeError::new_err("Invalid watch event kind")),
+    }
+}
+
+#[pyclass(name = "WatchEvent", get_all)]
+pub struct PyWatchEvent {
+    kind: &'static str,
+    path: String,
+    is_dir: bool,
+    cookie: u32,
+}
+
+impl From<bh_agent_common::WatchEvent> for PyWatchEvent {
+    fn from(event: bh_agent_common::WatchEvent) -> Self {
+        Self {
+            kind: watch_event_kind_name(event.kind),
+            path: event.path,
+            is_dir: event.is_dir,
+            cookie: event.cookie,
+        }
+    }
+}
+
+// Indexed by capability number, from linux/capability.h
+const CAPABILITY_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+// Bits without a known name are given by number, like cap_41
+fn capability_names(set: u64) -> Vec<String> {
+    (0..64)
+        .filter(|bit| set & (1 << bit) != 0)
+        .map(|bit| match CAPABILITY_NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("cap_{}", bit),
+        })
+        .collect()
+}
+
+/// Accepts names with or without the cap_ prefix, in any case, as well as numbered names.
+pub fn capability_set(names: &[String]) -> PyResult<u64> {
+    names.iter().try_fold(0, |set, name| {
+        let name = name.to_lowercase();
+        let name = match name.starts_with("cap_") {
+            true => name,
+            false => format!("cap_{}", name),
+        };
+        let bit = match CAPABILITY_NAMES.iter().position(|known| *known == name) {
+            Some(bit) => bit as u32,
+            None => name["cap_".len()..]
+                .parse::<u32>()
+                .ok()
+                .filter(|bit| *bit < 64)
+                .ok_or_else(|| PyRuntimeError::new_err("Unknown capability"))?,
+        };
+        Ok(set | 1 << bit)
+    })
+}
+
+#[pyclass(name = "FileCapabilities", get_all)]
+pub struct PyFileCapabilities {
+    permitted: Vec<String>,
+    inheritable: Vec<String>,
+    effective: bool,
+    rootid: Option<u32>,
+}
+
+impl From<bh_agent_common::FileCapabilities> for PyFileCapabilities {
+    fn from(caps: bh_agent_common::FileCapabilities) -> Self {
+        Self {
+            permitted: capability_names(caps.permitted),
+            inheritable: capability_names(caps.inheritable),
+            effective: caps.effective,
+            rootid: caps.rootid,
+        }
+    }
+}
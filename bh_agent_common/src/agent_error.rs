@@ -1,4 +1,7 @@
-use crate::AgentError::LockError;
+use crate::AgentError::{
+    AlreadyExists, DirectoryNotEmpty, IoError, IsADirectory, LockError, NotADirectory, NotFound,
+    PermissionDenied,
+};
 use serde::{Deserialize, Serialize};
 use std::sync::{PoisonError, RwLockReadGuard, RwLockWriteGuard};
 use thiserror::Error;
@@ -21,6 +24,58 @@ pub enum AgentError {
     InvalidProcessId,
     #[error("Process channel not piped")]
     ProcessChannelNotPiped,
+    #[error("Invalid process channel for this operation")]
+    InvalidProcessChannel,
+    #[error("Invalid pattern")]
+    InvalidPattern,
+    #[error("Operation timed out")]
+    Timeout,
+    #[error("End of file")]
+    EndOfFile,
+    #[error("No such file or directory")]
+    NotFound,
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("File exists")]
+    AlreadyExists,
+    #[error("Not a directory")]
+    NotADirectory,
+    #[error("Is a directory")]
+    IsADirectory,
+    #[error("Directory not empty")]
+    DirectoryNotEmpty,
+    #[error("Invalid offset")]
+    InvalidOffset,
+    #[error("Transferred file failed verification")]
+    HashMismatch,
+    #[error("Invalid or unsupported archive")]
+    InvalidArchive,
+    #[error("Archive entry would be extracted outside of the destination")]
+    UnsafeArchivePath,
+    #[error("Invalid content hash")]
+    InvalidHash,
+    #[error("Invalid watch ID")]
+    InvalidWatchId,
+    #[error("Invalid temporary file name")]
+    InvalidTempName,
+    #[error("Failed to decode text")]
+    TextDecodeError,
+    #[error("Failed to encode text")]
+    TextEncodeError,
+    #[error("File is locked")]
+    FileLocked,
+    #[error("No such attribute")]
+    NoSuchAttribute,
+    #[error("Invalid file capabilities")]
+    InvalidCapabilities,
+    #[error("Path is outside of the allowed roots")]
+    PathNotAllowed,
+    #[error("The agent is read-only")]
+    ReadOnly,
+    #[error("Request is too large")]
+    RequestTooLarge,
+    #[error("Not a regular file")]
+    NotARegularFile,
     #[error("The server state is inconsistent")]
     Inconsistent,
     #[error("Unknown Error")]
@@ -44,3 +99,17 @@ impl<T> From<RwLockWriteGuard<'_, T>> for AgentError {
         LockError
     }
 }
+
+impl From<std::io::Error> for AgentError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => NotFound,
+            std::io::ErrorKind::PermissionDenied => PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => AlreadyExists,
+            std::io::ErrorKind::NotADirectory => NotADirectory,
+            std::io::ErrorKind::IsADirectory => IsADirectory,
+            std::io::ErrorKind::DirectoryNotEmpty => DirectoryNotEmpty,
+            _ => IoError,
+        }
+    }
+}
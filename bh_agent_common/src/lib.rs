@@ -1,4 +1,5 @@
 mod agent_error;
+mod base64_bytes;
 mod service;
 mod types;
 
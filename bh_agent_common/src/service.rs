@@ -1,34 +1,135 @@
 use crate::agent_error::AgentError;
 use crate::{
-    EnvironmentId, FileId, FileOpenMode, FileOpenType, ProcessChannel, ProcessId, RemotePOpenConfig,
+    ArchiveFormat, ByteRange, CaptureTranscript, CollectedOutput, DetachedProcessInfo, DirEntry,
+    EnvironmentId, ExecutableSource, ExpectMatch, FileCapabilities, FileChunk, FileId,
+    FileOpenMode, FileOpenType, FileRef, FileStat, FileTransferInfo, HashAlgorithm, LockKind,
+    MatchPattern, ProcessChannel, ProcessId, ProcessTreeEntry, RemotePOpenConfig, ReplayReport,
+    ReplayTiming, SearchLimits, SearchResult, WatchEvent, WatchEventKind, WatchId,
 };
 use anyhow::Result;
 
 #[tarpc::service]
 pub trait BhAgentService {
+    // An agent can be restricted to a set of allowed root directories, along with the connection's
+    // private temporary directory. Every path given to it must then resolve into one of them, or
+    // the request fails with PathNotAllowed. Processes have to be given a working directory inside
+    // them, but that's all: a process that runs isn't jailed, and can read and write anything its
+    // user can. Files are opened beneath their root, but other operations check the path first and
+    // use it after, so something else on the host that swaps a symlink into the path in between can
+    // redirect them outside of the roots. The roots guard against mistakes, not against an
+    // adversary with write access inside them.
+    // A read-only agent refuses anything that would modify files with ReadOnly, including opening
+    // files for writing and making temporary files. It only runs the commands it was told to allow.
+    // Files it opens for reading keep their access times, where the agent is permitted to do that.
+
     // Environment enumeration
     async fn get_environments() -> Vec<EnvironmentId>;
 
     async fn get_tempdir(env_id: EnvironmentId) -> Result<String, AgentError>;
 
+    // Creates a uniquely named file or directory inside a directory private to the connection.
+    // Everything in it is deleted when the connection closes.
+    async fn make_temp(
+        env_id: EnvironmentId,
+        prefix: String,
+        suffix: String,
+        directory: bool,
+    ) -> Result<String, AgentError>;
+
     // Process management
     async fn run_command(
         env_id: EnvironmentId,
         config: RemotePOpenConfig,
     ) -> Result<ProcessId, AgentError>;
 
+    // Loads the executable into a memfd and executes it from there, so it never exists on disk.
+    // config.executable is ignored, and config.argv[0] is passed to the process as its argv[0].
+    async fn run_memfd_command(
+        env_id: EnvironmentId,
+        executable: ExecutableSource,
+        config: RemotePOpenConfig,
+    ) -> Result<ProcessId, AgentError>;
+
+    // Runs a process to completion in a single call, like Python's
+    // subprocess.run(capture_output=True). The redirections in the config are ignored; stdin is
+    // fed from the given bytes and then closed, and stdout and stderr are collected in full.
+    async fn run_and_collect(
+        env_id: EnvironmentId,
+        config: RemotePOpenConfig,
+        stdin: Vec<u8>,
+        timeout: Option<f64>,
+    ) -> Result<CollectedOutput, AgentError>;
+
+    // Runs a new process and feeds it the stdin recorded in a transcript created with
+    // RemotePOpenConfig::record, then compares its output to the recorded output. The redirections
+    // in the config are ignored.
+    async fn replay_transcript(
+        env_id: EnvironmentId,
+        transcript_path: String,
+        config: RemotePOpenConfig,
+        timing: ReplayTiming,
+        timeout: Option<f64>,
+    ) -> Result<ReplayReport, AgentError>;
+
     async fn get_process_channel(
         env_id: EnvironmentId,
         proc_id: ProcessId,
         channel: ProcessChannel,
     ) -> Result<FileId, AgentError>;
 
+    // Kills the process along with every process descended from it
+    async fn process_kill(env_id: EnvironmentId, proc_id: ProcessId) -> Result<(), AgentError>;
+
+    // Returns the process followed by its living descendants, including those that were
+    // reparented to the agent after their parent exited
+    async fn get_process_tree(
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> Result<Vec<ProcessTreeEntry>, AgentError>;
+
+    // Returns output captured with Redirection::Capture, starting at the chunk with sequence number
+    // start_seq. Chunks are returned in the order they were read, interleaving stdout and stderr.
+    async fn get_process_capture(
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        start_seq: u64,
+    ) -> Result<CaptureTranscript, AgentError>;
+
+    // Expect-style interaction. Output read while waiting for a match stays buffered on the agent,
+    // so anything after the match is returned by the next read of the channel.
+    async fn process_expect(
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        channel: ProcessChannel,
+        pattern: MatchPattern,
+        timeout: Option<f64>,
+    ) -> Result<ExpectMatch, AgentError>;
+
+    async fn process_sendline(
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        data: Vec<u8>,
+    ) -> Result<(), AgentError>;
+
+    // Detached processes outlive the connection that started them. Any connection can list them
+    // and attach to one, after which its channels are available through get_process_channel.
+    async fn list_detached_processes(
+        env_id: EnvironmentId,
+    ) -> Result<Vec<DetachedProcessInfo>, AgentError>;
+
+    async fn attach_process(env_id: EnvironmentId, proc_id: ProcessId) -> Result<(), AgentError>;
+
+    // Kills a detached process if it's still running, reaps it, and removes it from the agent and
+    // the calling connection.
+    async fn forget_process(env_id: EnvironmentId, proc_id: ProcessId) -> Result<(), AgentError>;
+
     // File IO
     // Implement most of the methods in binharness.IO, but omit ones that there can just be
     // replicated on the client side without a performance hit.
     // Data is represented as a Vec<u8> instead of a String because if we're in text mode, we can
     // just decode it on the client side. The server still needs to know the mode however, as an
-    // N length read in text mode will be N chars, not N bytes.
+    // N length read in text mode will be N chars, not N bytes. Text is always exchanged as UTF-8,
+    // and the server converts it to and from the file's encoding.
     async fn file_open(
         env_id: EnvironmentId,
         path: String,
@@ -48,6 +149,16 @@ pub trait BhAgentService {
         num_bytes: u32,
     ) -> Result<Vec<u8>, AgentError>;
 
+    // Lines keep their terminators. Text files end lines according to their newline mode, binary
+    // files at \n. Without a limit, a whole line is read.
+    async fn file_readline(
+        env_id: EnvironmentId,
+        fd: FileId,
+        limit: Option<u32>,
+    ) -> Result<Vec<u8>, AgentError>;
+
+    // Stops once the lines read add up to at least hint characters, or reads to the end of the
+    // file if hint is 0
     async fn file_read_lines(
         env_id: EnvironmentId,
         fd: FileId,
@@ -56,17 +167,333 @@ pub trait BhAgentService {
 
     async fn file_is_seekable(env_id: EnvironmentId, fd: FileId) -> Result<bool, AgentError>;
 
+    // Returns the new absolute position
     async fn file_seek(
         env_id: EnvironmentId,
         fd: FileId,
-        offset: i32,
+        offset: i64,
         whence: i32,
-    ) -> Result<(), AgentError>;
+    ) -> Result<u64, AgentError>;
 
-    async fn file_tell(env_id: EnvironmentId, fd: FileId) -> Result<i32, AgentError>;
+    async fn file_tell(env_id: EnvironmentId, fd: FileId) -> Result<u64, AgentError>;
+
+    // Resizes the file to size, or to the current position if no size is given, without moving
+    // the position. Returns the new size.
+    async fn file_truncate(
+        env_id: EnvironmentId,
+        fd: FileId,
+        size: Option<u64>,
+    ) -> Result<u64, AgentError>;
 
     async fn file_is_writable(env_id: EnvironmentId, fd: FileId) -> Result<bool, AgentError>;
 
     async fn file_write(env_id: EnvironmentId, fd: FileId, data: Vec<u8>)
         -> Result<(), AgentError>;
+
+    // Positional IO works at an absolute offset and leaves the position alone, so it is safe to
+    // use on a file shared between threads. A read is only short at the end of the file. Reads of
+    // more than 16 MiB are refused with RequestTooLarge.
+    async fn file_pread(
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        num_bytes: u32,
+    ) -> Result<Vec<u8>, AgentError>;
+
+    async fn file_pwrite(
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), AgentError>;
+
+    // Reads each (offset, num_bytes) pair in order, in a single round trip. The 16 MiB limit
+    // applies to the whole batch.
+    async fn file_pread_many(
+        env_id: EnvironmentId,
+        fd: FileId,
+        ranges: Vec<(u64, u32)>,
+    ) -> Result<Vec<Vec<u8>>, AgentError>;
+
+    async fn file_flush(env_id: EnvironmentId, fd: FileId) -> Result<(), AgentError>;
+
+    // Commits the file to disk. With data_only, metadata that isn't needed to read the data back
+    // is not flushed, like fdatasync.
+    async fn file_fsync(
+        env_id: EnvironmentId,
+        fd: FileId,
+        data_only: bool,
+    ) -> Result<(), AgentError>;
+
+    // Advisory flock() locks, held until they are unlocked or the file is closed. A blocking lock
+    // waits until the timeout or the request's deadline expires, whichever comes first.
+    async fn file_lock(
+        env_id: EnvironmentId,
+        fd: FileId,
+        kind: LockKind,
+        blocking: bool,
+        timeout: Option<f64>,
+    ) -> Result<(), AgentError>;
+
+    async fn file_unlock(env_id: EnvironmentId, fd: FileId) -> Result<(), AgentError>;
+
+    // Filesystem
+    // Paths are interpreted on the agent, relative to its working directory. Errors from the
+    // filesystem are reported as NotFound or PermissionDenied where possible.
+    async fn path_stat(env_id: EnvironmentId, path: String) -> Result<FileStat, AgentError>;
+
+    // Like path_stat, but doesn't follow a symlink at the end of the path
+    async fn path_lstat(env_id: EnvironmentId, path: String) -> Result<FileStat, AgentError>;
+
+    async fn fd_stat(env_id: EnvironmentId, fd: FileId) -> Result<FileStat, AgentError>;
+
+    // Lists the entries of a directory, optionally recursing into subdirectories. The pattern is a
+    // glob matched against the name of each entry.
+    async fn list_dir(
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        pattern: Option<String>,
+        with_stat: bool,
+    ) -> Result<Vec<DirEntry>, AgentError>;
+
+    async fn make_dirs(
+        env_id: EnvironmentId,
+        path: String,
+        mode: Option<u32>,
+        exist_ok: bool,
+    ) -> Result<(), AgentError>;
+
+    async fn remove(env_id: EnvironmentId, path: String, recursive: bool)
+        -> Result<(), AgentError>;
+
+    async fn rename(env_id: EnvironmentId, src: String, dst: String) -> Result<(), AgentError>;
+
+    // Sets the permission bits of a path, following symlinks
+    async fn chmod(env_id: EnvironmentId, path: String, mode: u32) -> Result<(), AgentError>;
+
+    // An owner or group that isn't given is left unchanged
+    async fn chown(
+        env_id: EnvironmentId,
+        path: String,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        follow_symlinks: bool,
+    ) -> Result<(), AgentError>;
+
+    // Creates a symlink at link_path pointing to target. With allowed roots, the target has to
+    // resolve into them as well, relative to the link's directory.
+    async fn symlink(
+        env_id: EnvironmentId,
+        target: String,
+        link_path: String,
+    ) -> Result<(), AgentError>;
+
+    // Creates a hard link at link_path to the file at target
+    async fn hardlink(
+        env_id: EnvironmentId,
+        target: String,
+        link_path: String,
+    ) -> Result<(), AgentError>;
+
+    async fn readlink(env_id: EnvironmentId, path: String) -> Result<String, AgentError>;
+
+    // Times are in nanoseconds since the Unix epoch. A time that isn't given is set to now.
+    async fn utime(
+        env_id: EnvironmentId,
+        path: String,
+        atime_ns: Option<i64>,
+        mtime_ns: Option<i64>,
+        follow_symlinks: bool,
+    ) -> Result<(), AgentError>;
+
+    // Extended attributes
+    // Values are raw bytes. Listing returns names sorted, including those in the security and
+    // trusted namespaces when the agent may see them.
+    async fn list_xattrs(
+        env_id: EnvironmentId,
+        path: String,
+        follow_symlinks: bool,
+    ) -> Result<Vec<String>, AgentError>;
+
+    async fn get_xattr(
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> Result<Vec<u8>, AgentError>;
+
+    async fn set_xattr(
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        value: Vec<u8>,
+        follow_symlinks: bool,
+    ) -> Result<(), AgentError>;
+
+    async fn remove_xattr(
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> Result<(), AgentError>;
+
+    // File capabilities are read from and written to security.capability. A file without any
+    // has None, and setting None removes them.
+    async fn get_capabilities(
+        env_id: EnvironmentId,
+        path: String,
+    ) -> Result<Option<FileCapabilities>, AgentError>;
+
+    async fn set_capabilities(
+        env_id: EnvironmentId,
+        path: String,
+        caps: Option<FileCapabilities>,
+    ) -> Result<(), AgentError>;
+
+    // Bulk transfers
+    // Uploads are written to a partial file next to the destination, which is only moved into
+    // place by upload_file_finish once its size and hash match. The partial file is keyed by the
+    // SHA-256 of the finished file and survives the connection, so an interrupted upload can resume
+    // from the offset given by upload_file_status.
+    async fn upload_file_status(
+        env_id: EnvironmentId,
+        path: String,
+        sha256: String,
+    ) -> Result<u64, AgentError>;
+
+    // Writes a chunk to the partial file, discarding anything after it, and returns the new size
+    // of the partial file. The chunk can't start past the end of the partial file.
+    async fn upload_file(
+        env_id: EnvironmentId,
+        path: String,
+        sha256: String,
+        chunk: FileChunk,
+    ) -> Result<u64, AgentError>;
+
+    // Verifies the partial file, then applies the mode and mtime and moves it into place. A
+    // partial file that fails verification is deleted.
+    async fn upload_file_finish(
+        env_id: EnvironmentId,
+        path: String,
+        info: FileTransferInfo,
+    ) -> Result<(), AgentError>;
+
+    // Only regular files can be downloaded, others are refused with NotARegularFile
+    async fn download_file_info(
+        env_id: EnvironmentId,
+        path: String,
+    ) -> Result<FileTransferInfo, AgentError>;
+
+    // Returns up to length bytes from the offset. The data is empty at end of file.
+    async fn download_file(
+        env_id: EnvironmentId,
+        path: String,
+        offset: u64,
+        length: u64,
+    ) -> Result<FileChunk, AgentError>;
+
+    // Archives
+    // Archives are moved with the bulk transfer RPCs and unpacked or packed in place on the agent.
+    // Extraction refuses entries that would land outside of the destination, and preserves
+    // permissions. The format is detected from the archive contents when it isn't given.
+    async fn extract_archive(
+        env_id: EnvironmentId,
+        archive_path: String,
+        dest_dir: String,
+        format: Option<ArchiveFormat>,
+    ) -> Result<(), AgentError>;
+
+    async fn pack_archive(
+        env_id: EnvironmentId,
+        src_dir: String,
+        archive_path: String,
+        format: ArchiveFormat,
+    ) -> Result<(), AgentError>;
+
+    // Hashes a file, or part of it, on the agent and returns the digest as lowercase hex. Open
+    // files are read without moving their position, so they have to be seekable.
+    async fn file_hash(
+        env_id: EnvironmentId,
+        file: FileRef,
+        algorithm: HashAlgorithm,
+        range: Option<ByteRange>,
+    ) -> Result<String, AgentError>;
+
+    // Searches a file, or every regular file under a directory, for a pattern. Matches don't
+    // overlap, and each comes with up to context bytes from either side of it. Symlinks aren't
+    // followed and unreadable files in a directory are skipped. The search stops early once it has
+    // the maximum number of matches or has read the maximum number of bytes. Files are scanned in
+    // windows, so regex matches longer than 64 KiB can be cut short. Searching something that isn't
+    // a regular file or directory, like a device, is refused with NotARegularFile.
+    async fn search(
+        env_id: EnvironmentId,
+        path: String,
+        pattern: MatchPattern,
+        context: u32,
+        limits: SearchLimits,
+    ) -> Result<SearchResult, AgentError>;
+
+    // Blob store
+    // Blobs are files stored on the agent by the lowercase hex SHA-256 of their contents. They
+    // outlive connections, so a client only has to upload what blob_missing reports.
+    async fn blob_missing(
+        env_id: EnvironmentId,
+        hashes: Vec<String>,
+    ) -> Result<Vec<String>, AgentError>;
+
+    // Uploads part of a blob, like upload_file
+    async fn blob_upload(
+        env_id: EnvironmentId,
+        hash: String,
+        chunk: FileChunk,
+    ) -> Result<u64, AgentError>;
+
+    // Adds an uploaded blob to the store once its contents match the hash
+    async fn blob_upload_finish(
+        env_id: EnvironmentId,
+        hash: String,
+        size: u64,
+    ) -> Result<(), AgentError>;
+
+    // Makes a blob available at a path, as a hard link to the read-only blob or as a copy. The mode
+    // only applies to copies. A hard link shares the blob's inode, so anything that changes it
+    // changes the blob too.
+    async fn blob_materialize(
+        env_id: EnvironmentId,
+        hash: String,
+        path: String,
+        hardlink: bool,
+        mode: Option<u32>,
+    ) -> Result<(), AgentError>;
+
+    // Runs a blob directly, ignoring the executable in the config
+    async fn run_blob_command(
+        env_id: EnvironmentId,
+        hash: String,
+        config: RemotePOpenConfig,
+    ) -> Result<ProcessId, AgentError>;
+
+    // Filesystem watches
+    // Events are queued on the agent from the moment the watch is created until it is removed or
+    // the connection closes. An empty list of event kinds watches for all of them. A recursive
+    // watch also watches directories created after it. Entries created in a new directory while it
+    // is being added to the watch can be reported twice.
+    async fn watch_path(
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        events: Vec<WatchEventKind>,
+    ) -> Result<WatchId, AgentError>;
+
+    // Returns up to max_events queued events, waiting for at least one until the timeout expires or
+    // the watch is removed
+    async fn watch_events(
+        env_id: EnvironmentId,
+        watch_id: WatchId,
+        max_events: u32,
+        timeout: Option<f64>,
+    ) -> Result<Vec<WatchEvent>, AgentError>;
+
+    async fn unwatch(env_id: EnvironmentId, watch_id: WatchId) -> Result<(), AgentError>;
 }
@@ -3,19 +3,24 @@ use serde::{Deserialize, Serialize};
 pub type EnvironmentId = u64;
 pub type ProcessId = u64;
 pub type FileId = u64;
+pub type WatchId = u64;
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum ProcessChannel {
     Stdin,
     Stdout,
     Stderr,
 }
 
-#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub enum Redirection {
     #[default]
     None,
     Save,
+    /// Output is continuously drained by the agent into a bounded buffer, and fetched with
+    /// get_process_capture rather than read through a file handle. Only valid for stdout and
+    /// stderr.
+    Capture,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -30,19 +35,339 @@ pub struct RemotePOpenConfig {
     pub setuid: Option<u32>,
     pub setgid: Option<u32>,
     pub setpgid: bool,
+    /// Detached processes are owned by the agent rather than the connection that started them,
+    /// so they keep running after the client disconnects and can be reattached to later.
+    #[serde(default)]
+    pub detached: bool,
+    /// Maximum number of bytes kept for captured output. The oldest output is dropped first.
+    #[serde(default)]
+    pub capture_buffer_size: Option<u64>,
+    /// Path of a transcript file on the agent to record all of the process's I/O into
+    #[serde(default)]
+    pub record: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessTreeEntry {
+    pub pid: u32,
+    pub ppid: u32,
+    pub name: String,
+}
+
+/// The executable image for a process run from memory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ExecutableSource {
+    Bytes(#[serde(with = "crate::base64_bytes")] Vec<u8>),
+    /// The full contents of an open file, regardless of its current position
+    File(FileId),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DetachedProcessInfo {
+    pub proc_id: ProcessId,
+    pub pid: Option<u32>,
+    pub argv: Vec<String>,
+    /// Address of the client that started the process
+    pub owner: String,
+    pub running: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MatchPattern {
+    /// A regular expression, matched against raw bytes
+    Regex(String),
+    /// An exact byte sequence
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpectMatch {
+    /// Output consumed before the match
+    pub before: Vec<u8>,
+    pub matched: Vec<u8>,
+    /// Capture groups of a regex pattern, excluding the whole match
+    pub captures: Vec<Option<Vec<u8>>>,
 }
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ExitStatus {
+    Exited(u32),
+    Signaled(u8),
+    Other(i32),
+    Undetermined,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CollectedOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: ExitStatus,
+    /// True if the process was killed because it ran past its timeout
+    pub timed_out: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputChunk {
+    /// Sequence number of the chunk, starting at 0 for each process
+    pub seq: u64,
+    pub channel: ProcessChannel,
+    /// Nanoseconds since the process was started, from a monotonic clock
+    pub timestamp_ns: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ReplayTiming {
+    /// Send each input at the time offset it was recorded at
+    Original,
+    /// Send each input as soon as the output recorded before it has arrived
+    Compressed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayDivergence {
+    pub channel: ProcessChannel,
+    /// Offset into the channel's output of the first byte that differs
+    pub offset: u64,
+    /// Recorded output starting at the offset, truncated
+    pub expected: Vec<u8>,
+    /// Replayed output starting at the offset, truncated
+    pub actual: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayReport {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: ExitStatus,
+    pub timed_out: bool,
+    /// At most one entry per output channel. Empty if the output matched the recording.
+    pub divergences: Vec<ReplayDivergence>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CaptureTranscript {
+    pub chunks: Vec<OutputChunk>,
+    /// Bytes dropped from the front of the buffer to keep it within its size limit
+    pub dropped_bytes: u64,
+    /// True once every captured channel has reached end of file
+    pub complete: bool,
+}
+
+/// The modes of Python's open(). The Update variants are the "+" modes, which can both read and
+/// write.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum FileOpenMode {
+    /// "r"
     Read,
+    /// "r+": the file must exist, and is not truncated
+    ReadUpdate,
+    /// "w": the file is created or truncated
     Write,
+    /// "w+"
+    WriteUpdate,
+    /// "x": the file must not exist yet
     ExclusiveWrite,
+    /// "x+"
+    ExclusiveWriteUpdate,
+    /// "a": the file is created if needed, and every write goes to the end
     Append,
-    Update,
+    /// "a+"
+    AppendUpdate,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum FileOpenType {
     Binary,
-    Text,
+    Text {
+        encoding: TextEncoding,
+        errors: TextErrors,
+        newline: NewlineMode,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Latin1,
+    Ascii,
+}
+
+/// What to do with bytes that can't be decoded, or characters that can't be encoded, named after
+/// Python's codec error handlers
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TextErrors {
+    Strict,
+    Ignore,
+    Replace,
+    BackslashReplace,
+}
+
+/// The newline argument of Python's open()
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum NewlineMode {
+    /// None: lines end at \n, \r or \r\n, which are all read as \n
+    Universal,
+    /// "": lines end at \n, \r or \r\n, which are read unchanged
+    Untranslated,
+    /// "\n": lines end at \n
+    Lf,
+    /// "\r": lines end at \r, and \n is written as \r
+    Cr,
+    /// "\r\n": lines end at \r\n, and \n is written as \r\n
+    CrLf,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+/// Decoded contents of the security.capability attribute. Capability sets are bitmasks indexed by
+/// capability number, so cap_net_raw (13) is 1 << 13.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FileCapabilities {
+    pub permitted: u64,
+    pub inheritable: u64,
+    pub effective: bool,
+    /// The user namespace root the capabilities apply to, for namespaced (revision 3) capabilities
+    pub rootid: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileStat {
+    pub file_type: FileType,
+    pub size: u64,
+    /// The full st_mode, including the file type bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub dev: u64,
+    pub inode: u64,
+    pub nlink: u64,
+    /// Timestamps are in nanoseconds since the Unix epoch
+    pub atime_ns: i64,
+    pub mtime_ns: i64,
+    pub ctime_ns: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirEntry {
+    /// Relative to the listed directory
+    pub path: String,
+    pub file_type: FileType,
+    /// Only set when requested. Symlinks are not followed.
+    pub stat: Option<FileStat>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchMatch {
+    /// Relative to the searched directory, or the searched path itself when it is a file
+    pub path: String,
+    /// Byte offset of the match in the file
+    pub offset: u64,
+    /// Starts at 1
+    pub line: u64,
+    pub matched: Vec<u8>,
+    /// Context bytes before and after the match
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+/// Limits that stop a search early. Unset limits don't apply.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SearchLimits {
+    pub max_matches: Option<u32>,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub matches: Vec<SearchMatch>,
+    pub bytes_scanned: u64,
+    /// Set when a limit stopped the search before everything was scanned
+    pub truncated: bool,
+}
+
+/// A piece of a file moved by upload_file or download_file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub offset: u64,
+    #[serde(with = "crate::base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Describes a whole file being transferred, used to verify it once every chunk has arrived
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileTransferInfo {
+    pub size: u64,
+    /// Permission bits only
+    pub mode: u32,
+    pub mtime_ns: i64,
+    /// Lowercase hex
+    pub sha256: String,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+/// A file given either by path or by an open file ID
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum FileRef {
+    Path(String),
+    File(FileId),
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    /// Up to the end of the file when not set
+    pub length: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum WatchEventKind {
+    Create,
+    Modify,
+    Delete,
+    MovedFrom,
+    MovedTo,
+    CloseWrite,
+    /// Events were lost because the queue filled up
+    Overflow,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub kind: WatchEventKind,
+    pub path: String,
+    pub is_dir: bool,
+    /// Pairs up the MovedFrom and MovedTo events of a rename
+    pub cookie: u32,
 }
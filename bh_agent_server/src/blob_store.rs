@@ -0,0 +1,163 @@
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bh_agent_common::AgentError::{InvalidHash, PermissionDenied};
+use bh_agent_common::{AgentError, FileChunk, FileTransferInfo};
+
+use crate::util::{check_sha256, upload_chunk, upload_finish};
+
+// Blobs are read-only so that writing to a path they're hard linked at fails instead of quietly
+// changing the blob. This doesn't protect them: the agent's user can still chmod the shared inode,
+// and root ignores the mode entirely.
+const BLOB_MODE: u32 = 0o555;
+
+/// Files stored on the agent by the SHA-256 of their contents, so clients only have to upload
+/// them once. Blobs are kept on disk and shared by every connection. The store usually lives in a
+/// shared temporary directory, so it's only used when it belongs to the agent's user and nobody
+/// else can write to it.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // Creates the root if needed, and checks that another user couldn't have planted blobs in it
+    fn root(&self) -> Result<&Path, AgentError> {
+        match DirBuilder::new().mode(0o700).create(&self.root) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+        let metadata = std::fs::symlink_metadata(&self.root)?;
+        if !metadata.is_dir()
+            || metadata.uid() != unsafe { libc::geteuid() }
+            || metadata.mode() & 0o077 != 0
+        {
+            return Err(PermissionDenied);
+        }
+        Ok(&self.root)
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf, AgentError> {
+        check_sha256(hash)?;
+        Ok(self.root()?.join(hash))
+    }
+
+    /// Returns the path a stored blob can be read from.
+    pub fn get(&self, hash: &str) -> Result<PathBuf, AgentError> {
+        let path = self.blob_path(hash)?;
+        std::fs::metadata(&path)?;
+        Ok(path)
+    }
+
+    /// Returns the hashes that aren't stored yet.
+    pub fn missing(&self, hashes: Vec<String>) -> Result<Vec<String>, AgentError> {
+        let mut missing = Vec::new();
+        for hash in hashes {
+            if !self.blob_path(&hash)?.exists() {
+                missing.push(hash);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn upload(&self, hash: &str, chunk: &FileChunk) -> Result<u64, AgentError> {
+        let path = self.blob_path(hash)?;
+        // The store's directory belongs to the agent alone, so it isn't confined to the roots
+        upload_chunk(&[], path.to_str().ok_or(InvalidHash)?, hash, chunk)
+    }
+
+    /// Verifies an uploaded blob against its hash and adds it to the store.
+    pub fn finish(&self, hash: &str, size: u64) -> Result<(), AgentError> {
+        let path = self.blob_path(hash)?;
+        let mtime_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as i64);
+        let info = FileTransferInfo {
+            size,
+            mode: BLOB_MODE,
+            mtime_ns,
+            sha256: hash.to_string(),
+        };
+        upload_finish(&[], path.to_str().ok_or(InvalidHash)?, &info)
+    }
+
+    /// Makes a blob available at a path. A hard link shares the blob's read-only inode, and falls
+    /// back to a copy when the path is on another filesystem. Copies are given the mode, and are
+    /// writable unless a mode says otherwise.
+    pub fn materialize(
+        &self,
+        hash: &str,
+        path: &str,
+        hardlink: bool,
+        mode: Option<u32>,
+    ) -> Result<(), AgentError> {
+        let blob = self.get(hash)?;
+        if hardlink {
+            match std::fs::hard_link(&blob, path) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        std::fs::copy(&blob, path)?;
+        let mode = mode.unwrap_or(0o755);
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_blob_store() {
+        let dir = TestDir::new("blob");
+        let store = BlobStore::new(dir.join("blobs"));
+        // sha256 of "abc"
+        let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let shared = BlobStore::new(dir.join("shared"));
+        std::fs::create_dir(dir.join("shared")).unwrap();
+        std::fs::set_permissions(dir.join("shared"), std::fs::Permissions::from_mode(0o777))
+            .unwrap();
+        assert!(matches!(
+            shared.missing(vec![hash.to_string()]),
+            Err(PermissionDenied)
+        ));
+
+        assert!(matches!(
+            store.missing(vec!["../etc/passwd".to_string()]),
+            Err(InvalidHash)
+        ));
+        assert_eq!(store.missing(vec![hash.to_string()]).unwrap(), vec![hash]);
+        let metadata = std::fs::metadata(dir.join("blobs")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o700);
+        let chunk = FileChunk {
+            offset: 0,
+            data: b"abc".to_vec(),
+        };
+        assert_eq!(store.upload(hash, &chunk).unwrap(), 3);
+        store.finish(hash, 3).unwrap();
+        assert!(store.missing(vec![hash.to_string()]).unwrap().is_empty());
+
+        let linked = dir.join("linked");
+        store
+            .materialize(hash, linked.to_str().unwrap(), true, None)
+            .unwrap();
+        assert_eq!(std::fs::metadata(&linked).unwrap().nlink(), 2);
+        let copied = dir.join("copied");
+        store
+            .materialize(hash, copied.to_str().unwrap(), false, Some(0o640))
+            .unwrap();
+        let metadata = std::fs::metadata(&copied).unwrap();
+        assert_eq!((metadata.nlink(), metadata.mode() & 0o7777), (1, 0o640));
+        assert_eq!(std::fs::read(&copied).unwrap(), b"abc");
+    }
+}
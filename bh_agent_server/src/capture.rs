@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use bh_agent_common::{AgentError, CaptureTranscript, OutputChunk, ProcessChannel};
+
+pub const DEFAULT_CAPTURE_BUFFER_SIZE: usize = 1024 * 1024;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+struct CaptureState {
+    chunks: VecDeque<OutputChunk>,
+    size: usize,
+    dropped_bytes: u64,
+    next_seq: u64,
+    open_channels: usize,
+}
+
+/// A bounded buffer of process output, tagged by channel and time. Background threads drain the
+/// captured pipes into it, so the process never blocks on a full pipe.
+pub struct CaptureBuffer {
+    start: Instant,
+    limit: usize,
+    state: RwLock<CaptureState>,
+}
+
+impl CaptureBuffer {
+    pub fn new(start: Instant, limit: usize) -> Self {
+        Self {
+            start,
+            limit,
+            state: RwLock::new(CaptureState {
+                chunks: VecDeque::new(),
+                size: 0,
+                dropped_bytes: 0,
+                next_seq: 0,
+                open_channels: 0,
+            }),
+        }
+    }
+
+    /// Starts a thread that drains the pipe into the buffer until end of file.
+    pub fn drain(self: &Arc<Self>, mut file: File, channel: ProcessChannel) {
+        if let Ok(mut state) = self.state.write() {
+            state.open_channels += 1;
+        }
+        let buffer = self.clone();
+        thread::spawn(move || {
+            let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+            loop {
+                match file.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => buffer.push(channel, chunk[..n].to_vec()),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+            if let Ok(mut state) = buffer.state.write() {
+                state.open_channels -= 1;
+            }
+        });
+    }
+
+    fn push(&self, channel: ProcessChannel, mut data: Vec<u8>) {
+        let timestamp_ns = self.start.elapsed().as_nanos() as u64;
+        let Ok(mut state) = self.state.write() else {
+            return;
+        };
+
+        // A single chunk larger than the whole buffer only keeps its tail
+        if data.len() > self.limit {
+            let excess = data.len() - self.limit;
+            data.drain(..excess);
+            state.dropped_bytes += excess as u64;
+        }
+        while state.size + data.len() > self.limit {
+            let Some(oldest) = state.chunks.pop_front() else {
+                break;
+            };
+            state.size -= oldest.data.len();
+            state.dropped_bytes += oldest.data.len() as u64;
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.size += data.len();
+        state.chunks.push_back(OutputChunk {
+            seq,
+            channel,
+            timestamp_ns,
+            data,
+        });
+    }
+
+    /// Returns the buffered chunks with a sequence number of at least start_seq.
+    pub fn transcript(&self, start_seq: u64) -> Result<CaptureTranscript, AgentError> {
+        let state = self.state.read()?;
+        Ok(CaptureTranscript {
+            chunks: state
+                .chunks
+                .iter()
+                .filter(|c| c.seq >= start_seq)
+                .cloned()
+                .collect(),
+            dropped_bytes: state.dropped_bytes,
+            complete: state.open_channels == 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_buffer_drops_oldest() {
+        let buffer = CaptureBuffer::new(Instant::now(), 8);
+        buffer.push(ProcessChannel::Stdout, b"abcd".to_vec());
+        buffer.push(ProcessChannel::Stderr, b"efgh".to_vec());
+        buffer.push(ProcessChannel::Stdout, b"ij".to_vec());
+
+        let transcript = buffer.transcript(0).unwrap();
+        assert_eq!(transcript.dropped_bytes, 4);
+        assert_eq!(
+            transcript
+                .chunks
+                .iter()
+                .map(|c| (c.seq, c.channel, c.data.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, ProcessChannel::Stderr, b"efgh".to_vec()),
+                (2, ProcessChannel::Stdout, b"ij".to_vec()),
+            ]
+        );
+        assert_eq!(buffer.transcript(2).unwrap().chunks.len(), 1);
+    }
+
+    #[test]
+    fn test_capture_buffer_oversized_chunk() {
+        let buffer = CaptureBuffer::new(Instant::now(), 4);
+        buffer.push(ProcessChannel::Stdout, b"abcdefgh".to_vec());
+
+        let transcript = buffer.transcript(0).unwrap();
+        assert_eq!(transcript.dropped_bytes, 4);
+        assert_eq!(transcript.chunks[0].data, b"efgh");
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use bh_agent_common::EnvironmentId;
+
+/// Agent-wide settings, fixed when the server starts.
+pub struct BhAgentConfig {
+    pub default_temp_root: PathBuf,
+    pub temp_roots: HashMap<EnvironmentId, PathBuf>,
+    /// Canonical directories that paths given to the agent must resolve into. Every path is
+    /// allowed when there are none. The connection's own temporary directory is always allowed.
+    pub allowed_roots: Vec<PathBuf>,
+    /// Refuses every request that would modify files, and runs only the allowed commands
+    pub read_only: bool,
+    /// Programs that may still be run in read-only mode, as absolute paths
+    pub allowed_commands: Vec<PathBuf>,
+}
+
+impl BhAgentConfig {
+    pub fn temp_root(&self, env_id: EnvironmentId) -> &Path {
+        self.temp_roots
+            .get(&env_id)
+            .unwrap_or(&self.default_temp_root)
+    }
+}
+
+impl Default for BhAgentConfig {
+    fn default() -> Self {
+        Self {
+            default_temp_root: std::env::temp_dir(),
+            temp_roots: HashMap::new(),
+            allowed_roots: Vec::new(),
+            read_only: false,
+            allowed_commands: Vec::new(),
+        }
+    }
+}
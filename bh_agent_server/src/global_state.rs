@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use bh_agent_common::AgentError::InvalidProcessId;
+use bh_agent_common::{AgentError, DetachedProcessInfo, ProcessId};
+
+use crate::blob_store::BlobStore;
+use crate::config::BhAgentConfig;
+use crate::process::ManagedProcess;
+use crate::process_tree::ProcessTracker;
+
+struct DetachedProcess {
+    proc: Arc<ManagedProcess>,
+    argv: Vec<String>,
+    owner: String,
+    // Session temporary directories of closed connections that the process might still be using
+    temp_dirs: Vec<PathBuf>,
+}
+
+/// State shared between every connection to the agent. Anything stored here survives a client
+/// disconnecting.
+pub struct BhAgentGlobalState {
+    pub config: BhAgentConfig,
+    detached_processes: RwLock<HashMap<ProcessId, DetachedProcess>>,
+    pub process_tracker: Arc<ProcessTracker>,
+    pub blob_store: BlobStore,
+
+    next_process_id: RwLock<ProcessId>,
+}
+
+impl BhAgentGlobalState {
+    pub fn new(config: BhAgentConfig) -> BhAgentGlobalState {
+        let blob_root = config.default_temp_root.join("bh_agent_blobs");
+        Self {
+            config,
+            detached_processes: RwLock::new(HashMap::new()),
+            process_tracker: ProcessTracker::new(),
+            blob_store: BlobStore::new(blob_root),
+
+            next_process_id: RwLock::new(0),
+        }
+    }
+
+    // Process IDs are allocated agent-wide so that a detached process keeps the same ID no matter
+    // which connection refers to it.
+    pub fn take_proc_id(&self) -> Result<ProcessId, AgentError> {
+        let mut next_process_id = self.next_process_id.write()?;
+        let process_id = *next_process_id;
+        *next_process_id += 1;
+        Ok(process_id)
+    }
+
+    pub fn add_detached_process(
+        &self,
+        proc_id: ProcessId,
+        proc: Arc<ManagedProcess>,
+        argv: Vec<String>,
+        owner: String,
+    ) -> Result<(), AgentError> {
+        self.detached_processes.write()?.insert(
+            proc_id,
+            DetachedProcess {
+                proc,
+                argv,
+                owner,
+                temp_dirs: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Keeps temporary directories around until a detached process is forgotten.
+    pub fn keep_temp_dirs(&self, proc_id: &ProcessId, dirs: &[PathBuf]) -> Result<(), AgentError> {
+        if let Some(detached) = self.detached_processes.write()?.get_mut(proc_id) {
+            detached.temp_dirs.extend_from_slice(dirs);
+        }
+        Ok(())
+    }
+
+    /// Removes a detached process, and returns the temporary directories that were only kept for
+    /// it.
+    pub fn remove_detached_process(
+        &self,
+        proc_id: &ProcessId,
+    ) -> Result<(Arc<ManagedProcess>, Vec<PathBuf>), AgentError> {
+        let mut detached_processes = self.detached_processes.write()?;
+        let mut detached = detached_processes.remove(proc_id).ok_or(InvalidProcessId)?;
+        detached.temp_dirs.retain(|dir| {
+            !detached_processes
+                .values()
+                .any(|other| other.temp_dirs.contains(dir))
+        });
+        Ok((detached.proc, detached.temp_dirs))
+    }
+
+    pub fn is_detached(&self, proc_id: &ProcessId) -> Result<bool, AgentError> {
+        Ok(self.detached_processes.read()?.contains_key(proc_id))
+    }
+
+    pub fn get_detached_process(
+        &self,
+        proc_id: &ProcessId,
+    ) -> Result<Arc<ManagedProcess>, AgentError> {
+        self.detached_processes
+            .read()?
+            .get(proc_id)
+            .map(|p| p.proc.clone())
+            .ok_or(InvalidProcessId)
+    }
+
+    pub fn list_detached_processes(&self) -> Result<Vec<DetachedProcessInfo>, AgentError> {
+        let mut infos = self
+            .detached_processes
+            .read()?
+            .iter()
+            .map(|(proc_id, detached)| {
+                let mut popen = detached.proc.popen.write()?;
+                Ok(DetachedProcessInfo {
+                    proc_id: *proc_id,
+                    pid: popen.pid(),
+                    argv: detached.argv.clone(),
+                    owner: detached.owner.clone(),
+                    running: popen.poll().is_none(),
+                })
+            })
+            .collect::<Result<Vec<_>, AgentError>>()?;
+        infos.sort_by_key(|info| info.proc_id);
+        Ok(infos)
+    }
+}
+
+impl Default for BhAgentGlobalState {
+    fn default() -> Self {
+        Self::new(BhAgentConfig::default())
+    }
+}
@@ -1,5 +1,17 @@
+mod blob_store;
+mod capture;
+mod config;
+mod global_state;
+mod process;
+mod process_tree;
+mod recording;
+mod replay;
 pub mod server;
 mod state;
 pub mod util;
+mod watch;
 
+pub use config::BhAgentConfig;
+pub use global_state::BhAgentGlobalState;
+pub use process_tree::become_subreaper;
 pub use server::BhAgentServer;
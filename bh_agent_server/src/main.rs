@@ -1,5 +1,7 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::Result;
 use futures::{future, prelude::*};
@@ -8,29 +10,79 @@ use tarpc::{
     tokio_serde::formats::Json,
 };
 
-use bh_agent_common::BhAgentService;
-use bh_agent_server::BhAgentServer;
+use bh_agent_common::{BhAgentService, EnvironmentId};
+use bh_agent_server::{become_subreaper, BhAgentConfig, BhAgentGlobalState, BhAgentServer};
 
-fn parse_args() -> Result<(IpAddr, u16)> {
+const USAGE: &str = concat!(
+    "<ip_addr> <port> [--temp-root [ENV_ID=]DIR]... [--allow-root DIR]... ",
+    "[--read-only] [--allow-command PATH]...",
+);
+
+fn parse_temp_root(config: &mut BhAgentConfig, value: &str) -> Result<()> {
+    match value.split_once('=') {
+        Some((env_id, dir)) => {
+            config
+                .temp_roots
+                .insert(env_id.parse::<EnvironmentId>()?, PathBuf::from(dir));
+        }
+        None => config.default_temp_root = PathBuf::from(value),
+    }
+    Ok(())
+}
+
+fn parse_args() -> Result<(IpAddr, u16, BhAgentConfig)> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        return Err(anyhow::anyhow!("Usage: {} <ip_addr> <port>", args[0]));
+    let usage = || anyhow::anyhow!("Usage: {} {}", args[0], USAGE);
+
+    let mut config = BhAgentConfig::default();
+    let mut positional = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--temp-root" => parse_temp_root(&mut config, rest.next().ok_or_else(usage)?)?,
+            // Roots are canonicalized up front, since paths are checked against them by prefix
+            "--allow-root" => config
+                .allowed_roots
+                .push(PathBuf::from(rest.next().ok_or_else(usage)?).canonicalize()?),
+            "--read-only" => config.read_only = true,
+            "--allow-command" => config
+                .allowed_commands
+                .push(PathBuf::from(rest.next().ok_or_else(usage)?)),
+            _ if arg.starts_with("--") => return Err(usage()),
+            _ => positional.push(arg),
+        }
+    }
+    let [ip_addr, port] = positional[..] else {
+        return Err(usage());
+    };
+    // Commands are only restricted in read-only mode, so allowing them otherwise would do nothing
+    if !config.allowed_commands.is_empty() && !config.read_only {
+        return Err(anyhow::anyhow!("--allow-command requires --read-only"));
     }
 
-    let ip_addr = IpAddr::from_str(&args[1])?;
-    let port = args[2].parse::<u16>()?;
+    let ip_addr = IpAddr::from_str(ip_addr)?;
+    let port = port.parse::<u16>()?;
 
-    Ok((ip_addr, port))
+    Ok((ip_addr, port, config))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let server_addr = parse_args().or_else(|e| -> Result<(IpAddr, u16)> {
+    let (ip_addr, port, config) = parse_args().unwrap_or_else(|e| {
         eprintln!("{}", e);
         std::process::exit(1);
-    })?;
+    });
+
+    // Processes that daemonize or double-fork are reparented to the agent, so they can still be
+    // tracked and cleaned up
+    if let Err(e) = become_subreaper() {
+        eprintln!("Failed to become a child subreaper: {}", e);
+    }
+
+    // Shared by every connection, so detached processes outlive their clients
+    let global_state = Arc::new(BhAgentGlobalState::new(config));
 
-    let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
+    let mut listener = tarpc::serde_transport::tcp::listen(&(ip_addr, port), Json::default).await?;
     listener.config_mut().max_frame_length(usize::MAX);
     listener
         // Ignore accept errors.
@@ -39,7 +91,10 @@ async fn main() -> anyhow::Result<()> {
         // serve is generated by the service attribute. It takes as input any type implementing
         // the generated World trait.
         .map(|channel| {
-            let server = BhAgentServer::new(channel.transport().peer_addr().unwrap());
+            let server = BhAgentServer::new(
+                channel.transport().peer_addr().unwrap(),
+                global_state.clone(),
+            );
             channel.execute(server.serve())
         })
         // Max 10 channels.
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use subprocess::Popen;
+
+use bh_agent_common::AgentError::{InvalidProcessChannel, ProcessChannelNotPiped};
+use bh_agent_common::{AgentError, ExitStatus, ProcessChannel, Redirection, RemotePOpenConfig};
+
+use crate::capture::{CaptureBuffer, DEFAULT_CAPTURE_BUFFER_SIZE};
+use crate::recording::Recorder;
+
+pub fn exit_status(status: subprocess::ExitStatus) -> ExitStatus {
+    match status {
+        subprocess::ExitStatus::Exited(code) => ExitStatus::Exited(code),
+        subprocess::ExitStatus::Signaled(signal) => ExitStatus::Signaled(signal),
+        subprocess::ExitStatus::Other(status) => ExitStatus::Other(status),
+        subprocess::ExitStatus::Undetermined => ExitStatus::Undetermined,
+    }
+}
+
+/// The read side of a process pipe.
+pub struct OutputChannel {
+    pub file: File,
+    /// Output the agent has already read from the pipe but not yet handed to a client.
+    pub buffer: Vec<u8>,
+}
+
+impl OutputChannel {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Removes up to n bytes from the front of the buffer, or returns None if nothing is buffered.
+    pub fn take_buffered(&mut self, n: usize) -> Option<Vec<u8>> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let n = n.min(self.buffer.len());
+        Some(self.buffer.drain(..n).collect())
+    }
+
+    /// Puts data back at the front of the buffer, to be read before the rest of the output.
+    pub fn unread(&mut self, data: Vec<u8>) {
+        self.buffer.splice(..0, data);
+    }
+}
+
+/// A process started by the agent. The pipes are taken out of the Popen and locked individually,
+/// so a blocking read on one channel doesn't hold up writes to another.
+// The pipes are declared before the Popen so they are closed before dropping it waits on the child.
+pub struct ManagedProcess {
+    pub stdin: Option<RwLock<File>>,
+    pub stdout: Option<RwLock<OutputChannel>>,
+    pub stderr: Option<RwLock<OutputChannel>>,
+    /// Output of channels redirected with Redirection::Capture
+    pub capture: Option<Arc<CaptureBuffer>>,
+    pub popen: RwLock<Popen>,
+}
+
+impl ManagedProcess {
+    pub fn new(
+        mut popen: Popen,
+        config: &RemotePOpenConfig,
+        start: Instant,
+        recorder: Option<Arc<Recorder>>,
+    ) -> Result<Self, AgentError> {
+        let capture = (config.stdout == Redirection::Capture
+            || config.stderr == Redirection::Capture)
+            .then(|| {
+                let limit = config
+                    .capture_buffer_size
+                    .map_or(DEFAULT_CAPTURE_BUFFER_SIZE, |s| s as usize);
+                Arc::new(CaptureBuffer::new(start, limit))
+            });
+
+        let mut stdin = popen.stdin.take();
+        let mut stdout = popen.stdout.take();
+        let mut stderr = popen.stderr.take();
+        if let Some(recorder) = &recorder {
+            stdin = stdin.map(|f| recorder.relay_stdin(f)).transpose()?;
+            stdout = stdout
+                .map(|f| recorder.relay_output(f, ProcessChannel::Stdout))
+                .transpose()?;
+            stderr = stderr
+                .map(|f| recorder.relay_output(f, ProcessChannel::Stderr))
+                .transpose()?;
+        }
+        if let Some(capture) = &capture {
+            if config.stdout == Redirection::Capture {
+                capture.drain(stdout.take().unwrap(), ProcessChannel::Stdout);
+            }
+            if config.stderr == Redirection::Capture {
+                capture.drain(stderr.take().unwrap(), ProcessChannel::Stderr);
+            }
+        }
+
+        Ok(Self {
+            stdin: stdin.map(RwLock::new),
+            stdout: stdout.map(|f| RwLock::new(OutputChannel::new(f))),
+            stderr: stderr.map(|f| RwLock::new(OutputChannel::new(f))),
+            capture,
+            popen: RwLock::new(popen),
+        })
+    }
+
+    pub fn output_channel(
+        &self,
+        channel: ProcessChannel,
+    ) -> Result<&RwLock<OutputChannel>, AgentError> {
+        match channel {
+            ProcessChannel::Stdin => Err(InvalidProcessChannel),
+            ProcessChannel::Stdout => self.stdout.as_ref().ok_or(ProcessChannelNotPiped),
+            ProcessChannel::Stderr => self.stderr.as_ref().ok_or(ProcessChannelNotPiped),
+        }
+    }
+}
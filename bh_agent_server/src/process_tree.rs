@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use subprocess::Popen;
+
+use bh_agent_common::{AgentError, ProcessId, ProcessTreeEntry};
+
+const SCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Makes the agent the subreaper for all of its descendants, so processes that double-fork or
+/// daemonize are reparented to the agent instead of init and remain visible to the tracker.
+pub fn become_subreaper() -> std::io::Result<()> {
+    match unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone)]
+struct ProcStat {
+    pid: u32,
+    ppid: u32,
+    state: char,
+    pgrp: u32,
+    session: u32,
+    name: String,
+    // Used together with the pid to tell processes apart when a pid is reused
+    start_time: u64,
+}
+
+fn parse_stat(stat: &str) -> Option<ProcStat> {
+    // The name is wrapped in parentheses and can contain anything, including spaces and
+    // parentheses, so split around the last closing parenthesis.
+    let (head, tail) = stat.rsplit_once(')')?;
+    let (pid, name) = head.split_once(" (")?;
+    let fields: Vec<&str> = tail.split_whitespace().collect();
+    Some(ProcStat {
+        pid: pid.trim().parse().ok()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        state: fields.first()?.chars().next()?,
+        pgrp: fields.get(2)?.parse().ok()?,
+        session: fields.get(3)?.parse().ok()?,
+        name: name.to_string(),
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+fn read_stat(pid: u32) -> Option<ProcStat> {
+    parse_stat(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+fn scan_proc() -> HashMap<u32, ProcStat> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().parse::<u32>().is_ok())
+        .filter_map(|e| std::fs::read_to_string(e.path().join("stat")).ok())
+        .filter_map(|stat| parse_stat(&stat))
+        .map(|stat| (stat.pid, stat))
+        .collect()
+}
+
+struct TrackedTree {
+    root: u32,
+    // The root's process group and session, which its descendants inherit unless they change them
+    pgrp: u32,
+    session: u32,
+    // pid -> start time
+    descendants: HashMap<u32, u64>,
+}
+
+#[derive(Default)]
+struct Trees {
+    trees: HashMap<ProcessId, TrackedTree>,
+    // pid -> start time of every process the tracker spawned that still exists. They're reaped by
+    // waiting on their Popen, so the tracker must never reap them itself.
+    spawned: HashMap<u32, u64>,
+    // pid -> start time of descendants of untracked trees that haven't been reaped yet
+    orphans: HashMap<u32, u64>,
+}
+
+/// Tracks every descendant of the processes started by the agent. A background thread rescans
+/// /proc periodically while anything is tracked, so short-lived intermediate processes are usually
+/// seen before they exit and their children are reparented to the agent. Processes that are
+/// reparented before they were ever seen can only be attributed to a tree that has its own process
+/// group or session.
+pub struct ProcessTracker {
+    trees: RwLock<Trees>,
+}
+
+impl ProcessTracker {
+    pub fn new() -> Arc<Self> {
+        let tracker = Arc::new(Self {
+            trees: RwLock::new(Trees::default()),
+        });
+        let weak = Arc::downgrade(&tracker);
+        thread::spawn(move || Self::scan_loop(weak));
+        tracker
+    }
+
+    fn scan_loop(tracker: Weak<Self>) {
+        while let Some(tracker) = tracker.upgrade() {
+            let idle = tracker
+                .trees
+                .read()
+                .is_ok_and(|t| t.trees.is_empty() && t.orphans.is_empty());
+            if !idle {
+                let _ = tracker.refresh();
+            }
+            drop(tracker);
+            thread::sleep(SCAN_INTERVAL);
+        }
+    }
+
+    /// Spawns a process and starts tracking it. Scanning is blocked while spawning, so the
+    /// process is known to be the tracker's own before any scan can see it.
+    pub fn spawn(
+        &self,
+        proc_id: ProcessId,
+        spawn: impl FnOnce() -> Result<Popen, AgentError>,
+    ) -> Result<Popen, AgentError> {
+        let mut trees = self.trees.write()?;
+        let popen = spawn()?;
+        // The child can't have been reaped yet, since only its Popen waits on it
+        if let Some(stat) = popen.pid().and_then(read_stat) {
+            trees.spawned.insert(stat.pid, stat.start_time);
+            trees.trees.insert(
+                proc_id,
+                TrackedTree {
+                    root: stat.pid,
+                    pgrp: stat.pgrp,
+                    session: stat.session,
+                    descendants: HashMap::new(),
+                },
+            );
+        }
+        Ok(popen)
+    }
+
+    /// Stops tracking a process. Its remaining descendants are still reaped once they exit.
+    pub fn untrack(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        let mut trees = self.trees.write()?;
+        if let Some(tree) = trees.trees.remove(proc_id) {
+            trees.orphans.extend(tree.descendants);
+        }
+        Ok(())
+    }
+
+    fn refresh(&self) -> Result<HashMap<u32, ProcStat>, AgentError> {
+        let procs = scan_proc();
+        let alive = |(pid, start_time): (&u32, &u64)| {
+            procs.get(pid).map(|p| p.start_time) == Some(*start_time)
+        };
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for stat in procs.values() {
+            children.entry(stat.ppid).or_default().push(stat.pid);
+        }
+
+        let mut trees = self.trees.write()?;
+        let trees = &mut *trees;
+        // Spawned processes that are gone have been waited on
+        trees
+            .spawned
+            .retain(|pid, start_time| alive((pid, start_time)));
+        trees
+            .orphans
+            .retain(|pid, start_time| alive((pid, start_time)));
+        let agent_pid = std::process::id();
+        let agent_pgrp = unsafe { libc::getpgrp() } as u32;
+        let agent_session = unsafe { libc::getsid(0) } as u32;
+
+        // Orphans reparented to the agent that no tree knows about yet are attributed through the
+        // process group or session they inherited
+        let known: HashSet<u32> = trees
+            .trees
+            .values()
+            .flat_map(|t| t.descendants.keys().copied())
+            .collect();
+        for stat in procs.values() {
+            if stat.ppid != agent_pid
+                || trees.spawned.contains_key(&stat.pid)
+                || known.contains(&stat.pid)
+            {
+                continue;
+            }
+            let tree = trees.trees.values_mut().find(|t| {
+                (t.pgrp != agent_pgrp && t.pgrp == stat.pgrp)
+                    || (t.session != agent_session && t.session == stat.session)
+            });
+            if let Some(tree) = tree {
+                tree.descendants.insert(stat.pid, stat.start_time);
+            }
+        }
+
+        for tree in trees.trees.values_mut() {
+            // Known descendants that are still alive stay in the tree even after being
+            // reparented to the agent
+            let mut found: HashMap<u32, u64> = tree
+                .descendants
+                .iter()
+                .filter(|entry| alive(*entry))
+                .map(|(pid, start_time)| (*pid, *start_time))
+                .collect();
+            let mut frontier: Vec<u32> = found.keys().copied().chain([tree.root]).collect();
+            while let Some(pid) = frontier.pop() {
+                for child in children.get(&pid).into_iter().flatten() {
+                    if *child != tree.root && !found.contains_key(child) {
+                        found.insert(*child, procs[child].start_time);
+                        frontier.push(*child);
+                    }
+                }
+            }
+            tree.descendants = found;
+        }
+
+        // As the subreaper, the agent inherits orphaned descendants and has to reap them. Processes
+        // it spawned itself are left alone, since they're waited on through their Popen even after
+        // they've been untracked.
+        for stat in procs.values() {
+            if stat.ppid == agent_pid && stat.state == 'Z' && !trees.spawned.contains_key(&stat.pid)
+            {
+                unsafe { libc::waitpid(stat.pid as i32, std::ptr::null_mut(), libc::WNOHANG) };
+            }
+        }
+
+        Ok(procs)
+    }
+
+    /// Returns the root process and its living descendants. Zombies are left out.
+    pub fn tree(&self, proc_id: &ProcessId) -> Result<Vec<ProcessTreeEntry>, AgentError> {
+        let procs = self.refresh()?;
+        let trees = self.trees.read()?;
+        let Some(tree) = trees.trees.get(proc_id) else {
+            return Ok(Vec::new());
+        };
+        let mut pids: Vec<u32> = tree.descendants.keys().copied().collect();
+        pids.sort();
+        Ok([tree.root]
+            .into_iter()
+            .chain(pids)
+            .filter_map(|pid| procs.get(&pid))
+            .filter(|stat| stat.state != 'Z')
+            .map(|stat| ProcessTreeEntry {
+                pid: stat.pid,
+                ppid: stat.ppid,
+                name: stat.name.clone(),
+            })
+            .collect())
+    }
+
+    /// Sends SIGKILL to every descendant of the process. The root itself is left to the caller,
+    /// which can kill it through its Popen without racing its reaping.
+    pub fn kill_descendants(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        let procs = self.refresh()?;
+        let trees = self.trees.read()?;
+        if let Some(tree) = trees.trees.get(proc_id) {
+            for (pid, start_time) in &tree.descendants {
+                if procs.get(pid).map(|p| p.start_time) == Some(*start_time) {
+                    unsafe { libc::kill(*pid as i32, libc::SIGKILL) };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (my (weird) proc) S 17 4242 4242 0 -1 4194560 108 0 0 0 0 0 0 0 20 0 1 \
+                    0 987654 4284416 192 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 3 0 0 0 \
+                    0 0";
+        let parsed = parse_stat(stat).unwrap();
+        assert_eq!(parsed.pid, 4242);
+        assert_eq!(parsed.ppid, 17);
+        assert_eq!(parsed.state, 'S');
+        assert_eq!(parsed.pgrp, 4242);
+        assert_eq!(parsed.session, 4242);
+        assert_eq!(parsed.name, "my (weird) proc");
+        assert_eq!(parsed.start_time, 987654);
+    }
+}
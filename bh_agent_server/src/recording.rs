@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use bh_agent_common::AgentError::IoError;
+use bh_agent_common::{AgentError, OutputChunk, ProcessChannel};
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+fn cloexec_pipe() -> std::io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+struct TranscriptWriter {
+    file: File,
+    next_seq: u64,
+}
+
+/// Records the I/O of a process into a transcript file, as one JSON encoded OutputChunk per line.
+/// Each pipe is relayed through a thread that records every chunk passing through it, so data is
+/// recorded the same way no matter how the client reads or writes it.
+pub struct Recorder {
+    start: Instant,
+    writer: RwLock<TranscriptWriter>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, start: Instant) -> Result<Self, AgentError> {
+        Ok(Self {
+            start,
+            writer: RwLock::new(TranscriptWriter {
+                file: File::create(path).map_err(|_| IoError)?,
+                next_seq: 0,
+            }),
+        })
+    }
+
+    fn record(&self, channel: ProcessChannel, data: &[u8]) {
+        let timestamp_ns = self.start.elapsed().as_nanos() as u64;
+        let Ok(mut writer) = self.writer.write() else {
+            return;
+        };
+        let chunk = OutputChunk {
+            seq: writer.next_seq,
+            channel,
+            timestamp_ns,
+            data: data.to_vec(),
+        };
+        writer.next_seq += 1;
+        if let Ok(mut line) = serde_json::to_vec(&chunk) {
+            line.push(b'\n');
+            let _ = writer.file.write_all(&line);
+        }
+    }
+
+    fn relay(self: &Arc<Self>, mut src: File, mut dst: File, channel: ProcessChannel) {
+        let recorder = self.clone();
+        thread::spawn(move || {
+            let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+            loop {
+                let n = match src.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                recorder.record(channel, &chunk[..n]);
+                if dst.write_all(&chunk[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Relays writes to the process's stdin. Returns the file the agent should write to instead.
+    pub fn relay_stdin(self: &Arc<Self>, stdin: File) -> Result<File, AgentError> {
+        let (read_end, write_end) = cloexec_pipe().map_err(|_| IoError)?;
+        self.relay(read_end, stdin, ProcessChannel::Stdin);
+        Ok(write_end)
+    }
+
+    /// Relays output of the process. Returns the file the agent should read from instead.
+    pub fn relay_output(
+        self: &Arc<Self>,
+        output: File,
+        channel: ProcessChannel,
+    ) -> Result<File, AgentError> {
+        let (read_end, write_end) = cloexec_pipe().map_err(|_| IoError)?;
+        self.relay(output, write_end, channel);
+        Ok(read_end)
+    }
+}
+
+pub fn read_transcript(path: &str) -> Result<Vec<OutputChunk>, AgentError> {
+    let file = File::open(path).map_err(|_| IoError)?;
+    BufReader::new(file)
+        .lines()
+        .map(|line| {
+            line.map_err(|_| IoError)
+                .and_then(|l| serde_json::from_str(&l).map_err(|_| IoError))
+        })
+        .collect()
+}
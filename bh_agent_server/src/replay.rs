@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use subprocess::Popen;
+
+use bh_agent_common::AgentError::{IoError, ProcessChannelNotPiped};
+use bh_agent_common::{
+    AgentError, ExitStatus, OutputChunk, ProcessChannel, ReplayDivergence, ReplayReport,
+    ReplayTiming,
+};
+
+use crate::process::exit_status;
+
+// How much of each side is included in a divergence report
+const DIVERGENCE_CONTEXT: usize = 64;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// With Compressed timing, how long to wait for missing output before sending the next input anyway
+const IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn collect(mut file: File) -> Arc<RwLock<Vec<u8>>> {
+    let output = Arc::new(RwLock::new(Vec::new()));
+    let collected = output.clone();
+    thread::spawn(move || {
+        let mut chunk = vec![0u8; 4096];
+        while let Ok(n) = file.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            match collected.write() {
+                Ok(mut out) => out.extend_from_slice(&chunk[..n]),
+                Err(_) => break,
+            }
+        }
+    });
+    output
+}
+
+fn output_len(output: &RwLock<Vec<u8>>) -> usize {
+    output.read().map_or(0, |o| o.len())
+}
+
+/// Finds where the actual output stops matching the recorded output, if anywhere.
+pub fn find_divergence(
+    channel: ProcessChannel,
+    expected: &[u8],
+    actual: &[u8],
+) -> Option<ReplayDivergence> {
+    let offset = expected
+        .iter()
+        .zip(actual)
+        .position(|(e, a)| e != a)
+        .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())))?;
+    let context = |data: &[u8]| data[offset..data.len().min(offset + DIVERGENCE_CONTEXT)].to_vec();
+    Some(ReplayDivergence {
+        channel,
+        offset: offset as u64,
+        expected: context(expected),
+        actual: context(actual),
+    })
+}
+
+/// Feeds the stdin side of a transcript to a freshly started process and compares its output with
+/// the recorded output. With Original timing, each input is sent at the same time offset it was
+/// recorded at. With Compressed timing, each input is sent as soon as all the output recorded
+/// before it has arrived, or once the process has produced no output for a while.
+pub fn replay(
+    mut proc: Popen,
+    transcript: &[OutputChunk],
+    timing: ReplayTiming,
+    timeout: Option<Duration>,
+) -> Result<ReplayReport, AgentError> {
+    let start = Instant::now();
+    let deadline = timeout.map(|t| start + t);
+    let past_deadline = || deadline.is_some_and(|d| Instant::now() >= d);
+
+    let mut stdin = proc.stdin.take().ok_or(ProcessChannelNotPiped)?;
+    let stdout = collect(proc.stdout.take().ok_or(ProcessChannelNotPiped)?);
+    let stderr = collect(proc.stderr.take().ok_or(ProcessChannelNotPiped)?);
+
+    let mut expected_stdout = Vec::new();
+    let mut expected_stderr = Vec::new();
+    let mut timed_out = false;
+    for chunk in transcript {
+        match chunk.channel {
+            ProcessChannel::Stdout => expected_stdout.extend_from_slice(&chunk.data),
+            ProcessChannel::Stderr => expected_stderr.extend_from_slice(&chunk.data),
+            ProcessChannel::Stdin => {
+                match timing {
+                    ReplayTiming::Original => {
+                        let at = start + Duration::from_nanos(chunk.timestamp_ns);
+                        let until = deadline.map_or(at, |d| at.min(d));
+                        thread::sleep(until.saturating_duration_since(Instant::now()));
+                    }
+                    ReplayTiming::Compressed => {
+                        let mut seen = (output_len(&stdout), output_len(&stderr));
+                        let mut last_output = Instant::now();
+                        while (seen.0 < expected_stdout.len() || seen.1 < expected_stderr.len())
+                            && last_output.elapsed() < IDLE_TIMEOUT
+                            && !past_deadline()
+                            && proc.poll().is_none()
+                        {
+                            thread::sleep(POLL_INTERVAL);
+                            let now_seen = (output_len(&stdout), output_len(&stderr));
+                            if now_seen != seen {
+                                seen = now_seen;
+                                last_output = Instant::now();
+                            }
+                        }
+                    }
+                }
+                if past_deadline() {
+                    timed_out = true;
+                    break;
+                }
+                // The process may legitimately exit before reading all of its input
+                if stdin.write_all(&chunk.data).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    drop(stdin);
+
+    let status = match deadline {
+        _ if timed_out => None,
+        Some(deadline) => proc
+            .wait_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| IoError)?,
+        None => Some(proc.wait().map_err(|_| IoError)?),
+    };
+    let status = match status {
+        Some(status) => exit_status(status),
+        None => {
+            timed_out = true;
+            proc.kill().map_err(|_| IoError)?;
+            proc.wait()
+                .map(exit_status)
+                .unwrap_or(ExitStatus::Undetermined)
+        }
+    };
+
+    // Give the collectors a moment to drain whatever the process wrote before exiting
+    let settle = Instant::now() + Duration::from_millis(100);
+    while Instant::now() < settle
+        && (output_len(&stdout) < expected_stdout.len()
+            || output_len(&stderr) < expected_stderr.len())
+    {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let stdout = stdout.read()?.clone();
+    let stderr = stderr.read()?.clone();
+    let divergences = [
+        find_divergence(ProcessChannel::Stdout, &expected_stdout, &stdout),
+        find_divergence(ProcessChannel::Stderr, &expected_stderr, &stderr),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    Ok(ReplayReport {
+        stdout,
+        stderr,
+        exit_status: status,
+        timed_out,
+        divergences,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_divergence() {
+        assert!(find_divergence(ProcessChannel::Stdout, b"same", b"same").is_none());
+
+        let d = find_divergence(ProcessChannel::Stdout, b"Hello, world", b"Hello, there").unwrap();
+        assert_eq!(d.offset, 7);
+        assert_eq!(d.expected, b"world");
+        assert_eq!(d.actual, b"there");
+
+        let d = find_divergence(ProcessChannel::Stderr, b"abc", b"abcdef").unwrap();
+        assert_eq!(d.offset, 3);
+        assert_eq!(d.expected, b"");
+        assert_eq!(d.actual, b"def");
+    }
+}
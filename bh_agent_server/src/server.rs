@@ -1,19 +1,34 @@
+use std::fs::{File, Permissions};
 use std::future::{ready, Ready};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Result;
+use futures::future::BoxFuture;
 use tarpc::context::Context;
 
-use bh_agent_common::AgentError::*;
 use bh_agent_common::{
-    AgentError, BhAgentService, EnvironmentId, FileId, FileOpenMode, FileOpenType, ProcessChannel,
-    ProcessId, RemotePOpenConfig,
+    AgentError, ArchiveFormat, BhAgentService, ByteRange, CaptureTranscript, CollectedOutput,
+    DetachedProcessInfo, DirEntry, EnvironmentId, ExecutableSource, ExpectMatch, FileCapabilities,
+    FileChunk, FileId, FileOpenMode, FileOpenType, FileRef, FileStat, FileTransferInfo,
+    HashAlgorithm, LockKind, MatchPattern, ProcessChannel, ProcessId, ProcessTreeEntry,
+    RemotePOpenConfig, ReplayReport, ReplayTiming, SearchLimits, SearchResult, WatchEvent,
+    WatchEventKind, WatchId,
 };
 
+use crate::global_state::BhAgentGlobalState;
 use crate::state::BhAgentState;
-use crate::util::{read_generic, read_lines};
+use crate::util::{
+    download_chunk, download_info, encode_text, extract_archive, file_stat, get_capabilities,
+    get_xattr, hash_file, list_dir, list_xattrs, lock_file, make_dirs, pack_archive, read_full_at,
+    remove, remove_xattr, search, set_capabilities, set_xattr, unlock_file, upload_chunk,
+    upload_finish, upload_status, utime, Matcher, MAX_READ_AT_SIZE,
+};
 
 macro_rules! check_env_id {
     ($env_id:expr) => {
@@ -21,19 +36,107 @@ macro_rules! check_env_id {
             return ready(Err(AgentError::InvalidEnvironmentId));
         }
     };
+    ($env_id:expr, boxed) => {
+        if $env_id != 0 {
+            return Box::pin(ready(Err(AgentError::InvalidEnvironmentId)));
+        }
+    };
+}
+
+// Confines a path to the allowed roots, returning early when it's outside of them. The path that
+// comes back is resolved, so it should be used in place of the one that was given. It's checked
+// before it's used, not as it's used, see BhAgentState::jail_path.
+macro_rules! jail_path {
+    ($self:expr, $path:expr, $follow_symlinks:expr) => {
+        match $self.state.jail_path(&$path, $follow_symlinks) {
+            Ok(path) => path,
+            Err(e) => return ready(Err(e)),
+        }
+    };
+    ($self:expr, $path:expr, $follow_symlinks:expr, boxed) => {
+        match $self.state.jail_path(&$path, $follow_symlinks) {
+            Ok(path) => path,
+            Err(e) => return Box::pin(ready(Err(e))),
+        }
+    };
+}
+
+// Refuses requests that modify files when the agent is read-only. Given a file, only refuses
+// writes to files opened with file_open.
+macro_rules! check_read_only {
+    ($self:expr) => {
+        if $self.state.read_only() {
+            return ready(Err(AgentError::ReadOnly));
+        }
+    };
+    ($self:expr, boxed) => {
+        if $self.state.read_only() {
+            return Box::pin(ready(Err(AgentError::ReadOnly)));
+        }
+    };
+    ($self:expr, $fd:expr) => {
+        if let Err(e) = $self.state.check_file_writable(&$fd) {
+            return ready(Err(e));
+        }
+    };
+}
+
+// Timeouts are given in seconds. Negative timeouts are treated as zero and infinite ones as no
+// timeout at all.
+fn timeout_duration(timeout: Option<f64>) -> Option<Duration> {
+    timeout.and_then(|t| Duration::try_from_secs_f64(t.max(0.0)).ok())
+}
+
+// Sets the flag when dropped, which tells a blocking thread that the request it's working for has
+// finished or been cancelled.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// Runs a request that can take a while on a blocking thread, so that it doesn't hold up the
+// executor, and every other request with it
+fn spawn_request<T, F>(f: F) -> BoxFuture<'static, Result<T, AgentError>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, AgentError> + Send + 'static,
+{
+    Box::pin(async move {
+        tokio::task::spawn_blocking(f)
+            .await
+            .unwrap_or(Err(AgentError::Unknown))
+    })
+}
+
+// Like spawn_request, for requests that wait on something. The function is given a flag that is
+// set once the request is cancelled, and should stop waiting then.
+fn spawn_cancellable<T, F>(f: F) -> BoxFuture<'static, Result<T, AgentError>>
+where
+    T: Send + 'static,
+    F: FnOnce(&AtomicBool) -> Result<T, AgentError> + Send + 'static,
+{
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let guard = CancelOnDrop(cancelled.clone());
+    Box::pin(async move {
+        let _guard = guard;
+        tokio::task::spawn_blocking(move || f(&cancelled))
+            .await
+            .unwrap_or(Err(AgentError::Unknown))
+    })
 }
 
 #[derive(Clone)]
 pub struct BhAgentServer {
-    sockaddr: SocketAddr,
     state: Arc<BhAgentState>,
 }
 
 impl BhAgentServer {
-    pub fn new(socket_addr: SocketAddr) -> Self {
+    pub fn new(socket_addr: SocketAddr, global_state: Arc<BhAgentGlobalState>) -> Self {
         Self {
-            sockaddr: socket_addr,
-            state: Arc::new(BhAgentState::new()),
+            state: Arc::new(BhAgentState::new(socket_addr, global_state)),
         }
     }
 }
@@ -50,7 +153,25 @@ impl BhAgentService for BhAgentServer {
     fn get_tempdir(self, _: Context, env_id: EnvironmentId) -> Self::GetTempdirFut {
         check_env_id!(env_id);
 
-        ready(Ok("/tmp".to_string())) // TODO: make configurable
+        ready(Ok(self
+            .state
+            .temp_root(env_id)
+            .to_string_lossy()
+            .into_owned()))
+    }
+
+    type MakeTempFut = Ready<Result<String, AgentError>>;
+    fn make_temp(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        prefix: String,
+        suffix: String,
+        directory: bool,
+    ) -> Self::MakeTempFut {
+        check_env_id!(env_id);
+
+        ready(self.state.make_temp(env_id, &prefix, &suffix, directory))
     }
 
     type RunCommandFut = Ready<Result<ProcessId, AgentError>>;
@@ -65,6 +186,56 @@ impl BhAgentService for BhAgentServer {
         ready(self.state.run_command(config))
     }
 
+    type RunMemfdCommandFut = Ready<Result<ProcessId, AgentError>>;
+    fn run_memfd_command(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        executable: ExecutableSource,
+        config: RemotePOpenConfig,
+    ) -> Self::RunMemfdCommandFut {
+        check_env_id!(env_id);
+
+        ready(self.state.run_memfd_command(executable, config))
+    }
+
+    type RunAndCollectFut = BoxFuture<'static, Result<CollectedOutput, AgentError>>;
+    fn run_and_collect(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        config: RemotePOpenConfig,
+        stdin: Vec<u8>,
+        timeout: Option<f64>,
+    ) -> Self::RunAndCollectFut {
+        check_env_id!(env_id, boxed);
+        let timeout = timeout_duration(timeout);
+
+        spawn_cancellable(move |cancelled| {
+            self.state
+                .run_and_collect(config, stdin, timeout, cancelled)
+        })
+    }
+
+    type ReplayTranscriptFut = BoxFuture<'static, Result<ReplayReport, AgentError>>;
+    fn replay_transcript(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        transcript_path: String,
+        config: RemotePOpenConfig,
+        timing: ReplayTiming,
+        timeout: Option<f64>,
+    ) -> Self::ReplayTranscriptFut {
+        check_env_id!(env_id, boxed);
+        let timeout = timeout_duration(timeout);
+
+        spawn_request(move || {
+            self.state
+                .replay_transcript(&transcript_path, config, timing, timeout)
+        })
+    }
+
     type GetProcessChannelFut = Ready<Result<FileId, AgentError>>;
     fn get_process_channel(
         self,
@@ -78,6 +249,115 @@ impl BhAgentService for BhAgentServer {
         ready(self.state.get_process_channel(&proc_id, channel))
     }
 
+    type ProcessKillFut = Ready<Result<(), AgentError>>;
+    fn process_kill(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> Self::ProcessKillFut {
+        check_env_id!(env_id);
+
+        ready(self.state.process_kill(&proc_id))
+    }
+
+    type GetProcessTreeFut = Ready<Result<Vec<ProcessTreeEntry>, AgentError>>;
+    fn get_process_tree(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> Self::GetProcessTreeFut {
+        check_env_id!(env_id);
+
+        ready(self.state.get_process_tree(&proc_id))
+    }
+
+    type GetProcessCaptureFut = Ready<Result<CaptureTranscript, AgentError>>;
+    fn get_process_capture(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        start_seq: u64,
+    ) -> Self::GetProcessCaptureFut {
+        check_env_id!(env_id);
+
+        ready(self.state.get_process_capture(&proc_id, start_seq))
+    }
+
+    type ProcessExpectFut = BoxFuture<'static, Result<ExpectMatch, AgentError>>;
+    fn process_expect(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        channel: ProcessChannel,
+        pattern: MatchPattern,
+        timeout: Option<f64>,
+    ) -> Self::ProcessExpectFut {
+        check_env_id!(env_id, boxed);
+        let matcher = match Matcher::new(&pattern) {
+            Ok(matcher) => matcher,
+            Err(e) => return Box::pin(ready(Err(e))),
+        };
+        let timeout = timeout_duration(timeout);
+
+        // Output may never come, so the wait happens on a blocking thread
+        spawn_cancellable(move |cancelled| {
+            self.state
+                .process_expect(&proc_id, channel, &matcher, timeout, cancelled)
+        })
+    }
+
+    type ProcessSendlineFut = Ready<Result<(), AgentError>>;
+    fn process_sendline(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+        data: Vec<u8>,
+    ) -> Self::ProcessSendlineFut {
+        check_env_id!(env_id);
+
+        ready(self.state.process_sendline(&proc_id, &data))
+    }
+
+    type ListDetachedProcessesFut = Ready<Result<Vec<DetachedProcessInfo>, AgentError>>;
+    fn list_detached_processes(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+    ) -> Self::ListDetachedProcessesFut {
+        check_env_id!(env_id);
+
+        ready(self.state.list_detached_processes())
+    }
+
+    type ForgetProcessFut = Ready<Result<(), AgentError>>;
+    fn forget_process(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> Self::ForgetProcessFut {
+        check_env_id!(env_id);
+
+        ready(self.state.forget_process(&proc_id))
+    }
+
+    type AttachProcessFut = Ready<Result<(), AgentError>>;
+    fn attach_process(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        proc_id: ProcessId,
+    ) -> Self::AttachProcessFut {
+        check_env_id!(env_id);
+
+        ready(self.state.attach_process(&proc_id))
+    }
+
     type FileOpenFut = Ready<Result<FileId, AgentError>>;
     fn file_open(
         self,
@@ -120,10 +400,16 @@ impl BhAgentService for BhAgentServer {
     ) -> Self::FileIsReadableFut {
         check_env_id!(env_id);
 
-        ready(
-            self.state
-                .file_has_any_mode(&fd, &vec![FileOpenMode::Read, FileOpenMode::Update]),
-        )
+        ready(self.state.file_has_any_mode(
+            &fd,
+            &[
+                FileOpenMode::Read,
+                FileOpenMode::ReadUpdate,
+                FileOpenMode::WriteUpdate,
+                FileOpenMode::ExclusiveWriteUpdate,
+                FileOpenMode::AppendUpdate,
+            ],
+        ))
     }
 
     type FileReadFut = Ready<Result<Vec<u8>, AgentError>>;
@@ -136,15 +422,51 @@ impl BhAgentService for BhAgentServer {
     ) -> Self::FileReadFut {
         check_env_id!(env_id);
 
+        let file_type = match self.state.file_type(&fd) {
+            Ok(file_type) => file_type,
+            Err(e) => return ready(Err(e)),
+        };
+        if let FileOpenType::Text { .. } = file_type {
+            return ready(
+                self.state
+                    .read_text(&fd, |reader| reader.read_chars(num_bytes as usize)),
+            );
+        }
+
+        // Output buffered by process_expect comes before anything still in the pipe
+        match self.state.take_buffered_output(&fd, num_bytes as usize) {
+            Ok(Some(buffered)) => return ready(Ok(buffered)),
+            Ok(None) => {}
+            Err(e) => return ready(Err(e)),
+        }
+
         ready(
             self.state
                 .do_mut_operation(&fd, |file| {
-                    read_generic(file, num_bytes, self.state.file_type(&fd)?)
+                    let mut buffer = vec![0u8; num_bytes as usize];
+                    let bytes_read = file.read(&mut buffer)?;
+                    buffer.truncate(bytes_read);
+                    Ok(buffer)
                 })
-                .and_then(|v| v.map_err(|_| IoError)),
+                .and_then(|r: std::io::Result<Vec<u8>>| r.map_err(AgentError::from)),
         )
     }
 
+    type FileReadlineFut = Ready<Result<Vec<u8>, AgentError>>;
+    fn file_readline(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        limit: Option<u32>,
+    ) -> Self::FileReadlineFut {
+        check_env_id!(env_id);
+
+        ready(self.state.read_text(&fd, |reader| {
+            reader.read_line(limit.map(|limit| limit as usize))
+        }))
+    }
+
     type FileReadLinesFut = Ready<Result<Vec<Vec<u8>>, AgentError>>;
     fn file_read_lines(
         self,
@@ -155,11 +477,9 @@ impl BhAgentService for BhAgentServer {
     ) -> Self::FileReadLinesFut {
         check_env_id!(env_id);
 
-        // TODO: support hint
         ready(
             self.state
-                .do_mut_operation(&fd, |file| read_lines(file).map_err(|_| IoError))
-                .and_then(|r| r),
+                .read_text(&fd, |reader| reader.read_lines(hint as usize)),
         )
     }
 
@@ -172,39 +492,70 @@ impl BhAgentService for BhAgentServer {
     ) -> Self::FileIsSeekableFut {
         check_env_id!(env_id);
 
-        todo!()
+        // Same test as Python's seekable(): pipes and sockets fail to report a position
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| file.stream_position().is_ok()),
+        )
     }
 
-    type FileSeekFut = Ready<Result<(), AgentError>>;
+    type FileSeekFut = Ready<Result<u64, AgentError>>;
     fn file_seek(
         self,
         _: Context,
         env_id: EnvironmentId,
         fd: FileId,
-        offset: i32,
+        offset: i64,
         whence: i32,
     ) -> Self::FileSeekFut {
         check_env_id!(env_id);
 
         let from = match whence {
-            0 => SeekFrom::Start(offset as u64),
-            1 => SeekFrom::Current(offset as i64),
-            2 => SeekFrom::End(offset as i64),
+            0 => match u64::try_from(offset) {
+                Ok(offset) => SeekFrom::Start(offset),
+                Err(_) => return ready(Err(AgentError::InvalidOffset)),
+            },
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
             _ => return ready(Err(AgentError::InvalidSeekWhence)),
         };
 
+        ready(self.state.seek_file(&fd, from))
+    }
+
+    type FileTellFut = Ready<Result<u64, AgentError>>;
+    fn file_tell(self, _: Context, env_id: EnvironmentId, fd: FileId) -> Self::FileTellFut {
+        check_env_id!(env_id);
+
         ready(
             self.state
-                .do_mut_operation(&fd, |file| file.seek(from))
-                .map(|_| ()),
+                .do_mut_operation(&fd, |file| file.stream_position())
+                .and_then(|r| r.map_err(AgentError::from)),
         )
     }
 
-    type FileTellFut = Ready<Result<i32, AgentError>>;
-    fn file_tell(self, _: Context, env_id: EnvironmentId, fd: FileId) -> Self::FileTellFut {
+    type FileTruncateFut = Ready<Result<u64, AgentError>>;
+    fn file_truncate(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        size: Option<u64>,
+    ) -> Self::FileTruncateFut {
         check_env_id!(env_id);
+        check_read_only!(self, fd);
 
-        todo!()
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| {
+                    let size = match size {
+                        Some(size) => size,
+                        None => file.stream_position()?,
+                    };
+                    file.set_len(size).map(|_| size)
+                })
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
     }
 
     type FileIsWritableFut = Ready<Result<bool, AgentError>>;
@@ -218,11 +569,14 @@ impl BhAgentService for BhAgentServer {
 
         ready(self.state.file_has_any_mode(
             &fd,
-            &vec![
+            &[
+                FileOpenMode::ReadUpdate,
                 FileOpenMode::Write,
+                FileOpenMode::WriteUpdate,
                 FileOpenMode::ExclusiveWrite,
-                FileOpenMode::Update,
+                FileOpenMode::ExclusiveWriteUpdate,
                 FileOpenMode::Append,
+                FileOpenMode::AppendUpdate,
             ],
         ))
     }
@@ -236,11 +590,1041 @@ impl BhAgentService for BhAgentServer {
         data: Vec<u8>,
     ) -> Self::FileWriteFut {
         check_env_id!(env_id);
+        check_read_only!(self, fd);
+
+        let data = match self
+            .state
+            .file_type(&fd)
+            .and_then(|file_type| encode_text(&data, file_type))
+        {
+            Ok(data) => data,
+            Err(e) => return ready(Err(e)),
+        };
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| file.write_all(&data))
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FilePreadFut = Ready<Result<Vec<u8>, AgentError>>;
+    fn file_pread(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        num_bytes: u32,
+    ) -> Self::FilePreadFut {
+        check_env_id!(env_id);
+        if num_bytes as u64 > MAX_READ_AT_SIZE {
+            return ready(Err(AgentError::RequestTooLarge));
+        }
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| read_full_at(file, offset, num_bytes as usize))
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FilePwriteFut = Ready<Result<(), AgentError>>;
+    fn file_pwrite(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Self::FilePwriteFut {
+        check_env_id!(env_id);
+        check_read_only!(self, fd);
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| file.write_all_at(&data, offset))
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FilePreadManyFut = Ready<Result<Vec<Vec<u8>>, AgentError>>;
+    fn file_pread_many(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        ranges: Vec<(u64, u32)>,
+    ) -> Self::FilePreadManyFut {
+        check_env_id!(env_id);
+        if ranges.iter().map(|r| r.1 as u64).sum::<u64>() > MAX_READ_AT_SIZE {
+            return ready(Err(AgentError::RequestTooLarge));
+        }
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| {
+                    ranges
+                        .iter()
+                        .map(|&(offset, num_bytes)| read_full_at(file, offset, num_bytes as usize))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FileFlushFut = Ready<Result<(), AgentError>>;
+    fn file_flush(self, _: Context, env_id: EnvironmentId, fd: FileId) -> Self::FileFlushFut {
+        check_env_id!(env_id);
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| file.flush())
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FileFsyncFut = Ready<Result<(), AgentError>>;
+    fn file_fsync(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        data_only: bool,
+    ) -> Self::FileFsyncFut {
+        check_env_id!(env_id);
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| {
+                    if data_only {
+                        file.sync_data()
+                    } else {
+                        file.sync_all()
+                    }
+                })
+                .and_then(|r| r.map_err(AgentError::from)),
+        )
+    }
+
+    type FileLockFut = BoxFuture<'static, Result<(), AgentError>>;
+    fn file_lock(
+        self,
+        ctx: Context,
+        env_id: EnvironmentId,
+        fd: FileId,
+        kind: LockKind,
+        blocking: bool,
+        timeout: Option<f64>,
+    ) -> Self::FileLockFut {
+        check_env_id!(env_id, boxed);
+        let file = match self
+            .state
+            .do_mut_operation(&fd, |file| file.try_clone())
+            .and_then(|r| r.map_err(AgentError::from))
+        {
+            Ok(file) => file,
+            Err(e) => return Box::pin(ready(Err(e))),
+        };
+
+        // Nobody is waiting for the lock past the request's deadline
+        let remaining = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let timeout = Some(timeout_duration(timeout).map_or(remaining, |t| t.min(remaining)));
+
+        // Waiting for a lock can take arbitrarily long. The lock belongs to the open file, which
+        // the duplicate shares.
+        spawn_cancellable(move |cancelled| lock_file(&file, kind, blocking, timeout, cancelled))
+    }
+
+    type FileUnlockFut = Ready<Result<(), AgentError>>;
+    fn file_unlock(self, _: Context, env_id: EnvironmentId, fd: FileId) -> Self::FileUnlockFut {
+        check_env_id!(env_id);
 
         ready(
             self.state
-                .do_mut_operation(&fd, |file| file.write(&data))
-                .map(|_| ()),
+                .do_mut_operation(&fd, |file| unlock_file(file))
+                .and_then(|r| r),
         )
     }
+
+    type PathStatFut = Ready<Result<FileStat, AgentError>>;
+    fn path_stat(self, _: Context, env_id: EnvironmentId, path: String) -> Self::PathStatFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(
+            std::fs::metadata(path)
+                .map(|m| file_stat(&m))
+                .map_err(AgentError::from),
+        )
+    }
+
+    type PathLstatFut = Ready<Result<FileStat, AgentError>>;
+    fn path_lstat(self, _: Context, env_id: EnvironmentId, path: String) -> Self::PathLstatFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, false);
+
+        ready(
+            std::fs::symlink_metadata(path)
+                .map(|m| file_stat(&m))
+                .map_err(AgentError::from),
+        )
+    }
+
+    type FdStatFut = Ready<Result<FileStat, AgentError>>;
+    fn fd_stat(self, _: Context, env_id: EnvironmentId, fd: FileId) -> Self::FdStatFut {
+        check_env_id!(env_id);
+
+        ready(
+            self.state
+                .do_mut_operation(&fd, |file| file.metadata())
+                .and_then(|m| Ok(file_stat(&m?))),
+        )
+    }
+
+    type ListDirFut = Ready<Result<Vec<DirEntry>, AgentError>>;
+    fn list_dir(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        pattern: Option<String>,
+        with_stat: bool,
+    ) -> Self::ListDirFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(list_dir(&path, recursive, pattern.as_deref(), with_stat))
+    }
+
+    type MakeDirsFut = Ready<Result<(), AgentError>>;
+    fn make_dirs(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        mode: Option<u32>,
+        exist_ok: bool,
+    ) -> Self::MakeDirsFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, true);
+
+        ready(make_dirs(&path, mode, exist_ok))
+    }
+
+    type RemoveFut = Ready<Result<(), AgentError>>;
+    fn remove(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+    ) -> Self::RemoveFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, false);
+
+        ready(remove(&path, recursive))
+    }
+
+    type RenameFut = Ready<Result<(), AgentError>>;
+    fn rename(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        src: String,
+        dst: String,
+    ) -> Self::RenameFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let src = jail_path!(self, src, false);
+        let dst = jail_path!(self, dst, false);
+
+        ready(std::fs::rename(src, dst).map_err(AgentError::from))
+    }
+
+    type ChmodFut = Ready<Result<(), AgentError>>;
+    fn chmod(self, _: Context, env_id: EnvironmentId, path: String, mode: u32) -> Self::ChmodFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, true);
+
+        ready(
+            std::fs::set_permissions(path, Permissions::from_mode(mode)).map_err(AgentError::from),
+        )
+    }
+
+    type ChownFut = Ready<Result<(), AgentError>>;
+    fn chown(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        follow_symlinks: bool,
+    ) -> Self::ChownFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(
+            match follow_symlinks {
+                true => std::os::unix::fs::chown(path, uid, gid),
+                false => std::os::unix::fs::lchown(path, uid, gid),
+            }
+            .map_err(AgentError::from),
+        )
+    }
+
+    type SymlinkFut = Ready<Result<(), AgentError>>;
+    fn symlink(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        target: String,
+        link_path: String,
+    ) -> Self::SymlinkFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let link_path = jail_path!(self, link_path, false);
+        // A relative target is resolved from the directory the link is in, like it will be when
+        // the link is followed. The target is still stored as given.
+        let resolved_target = Path::new(&link_path)
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join(&target);
+        jail_path!(self, resolved_target.to_string_lossy(), true);
+
+        ready(std::os::unix::fs::symlink(target, link_path).map_err(AgentError::from))
+    }
+
+    type HardlinkFut = Ready<Result<(), AgentError>>;
+    fn hardlink(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        target: String,
+        link_path: String,
+    ) -> Self::HardlinkFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let target = jail_path!(self, target, false);
+        let link_path = jail_path!(self, link_path, false);
+
+        ready(std::fs::hard_link(target, link_path).map_err(AgentError::from))
+    }
+
+    type ReadlinkFut = Ready<Result<String, AgentError>>;
+    fn readlink(self, _: Context, env_id: EnvironmentId, path: String) -> Self::ReadlinkFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, false);
+
+        ready(
+            std::fs::read_link(path)
+                .map(|target| target.to_string_lossy().into_owned())
+                .map_err(AgentError::from),
+        )
+    }
+
+    type UtimeFut = Ready<Result<(), AgentError>>;
+    fn utime(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        atime_ns: Option<i64>,
+        mtime_ns: Option<i64>,
+        follow_symlinks: bool,
+    ) -> Self::UtimeFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(utime(&path, atime_ns, mtime_ns, follow_symlinks))
+    }
+
+    type ListXattrsFut = Ready<Result<Vec<String>, AgentError>>;
+    fn list_xattrs(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        follow_symlinks: bool,
+    ) -> Self::ListXattrsFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(list_xattrs(&path, follow_symlinks))
+    }
+
+    type GetXattrFut = Ready<Result<Vec<u8>, AgentError>>;
+    fn get_xattr(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> Self::GetXattrFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(get_xattr(&path, &name, follow_symlinks))
+    }
+
+    type SetXattrFut = Ready<Result<(), AgentError>>;
+    fn set_xattr(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        value: Vec<u8>,
+        follow_symlinks: bool,
+    ) -> Self::SetXattrFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(set_xattr(&path, &name, &value, follow_symlinks))
+    }
+
+    type RemoveXattrFut = Ready<Result<(), AgentError>>;
+    fn remove_xattr(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        name: String,
+        follow_symlinks: bool,
+    ) -> Self::RemoveXattrFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, follow_symlinks);
+
+        ready(remove_xattr(&path, &name, follow_symlinks))
+    }
+
+    type GetCapabilitiesFut = Ready<Result<Option<FileCapabilities>, AgentError>>;
+    fn get_capabilities(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+    ) -> Self::GetCapabilitiesFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(get_capabilities(&path))
+    }
+
+    type SetCapabilitiesFut = Ready<Result<(), AgentError>>;
+    fn set_capabilities(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        caps: Option<FileCapabilities>,
+    ) -> Self::SetCapabilitiesFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, true);
+
+        ready(set_capabilities(&path, caps.as_ref()))
+    }
+
+    type UploadFileStatusFut = Ready<Result<u64, AgentError>>;
+    fn upload_file_status(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        sha256: String,
+    ) -> Self::UploadFileStatusFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(
+            self.state
+                .allowed_roots()
+                .and_then(|roots| upload_status(&roots, &path, &sha256)),
+        )
+    }
+
+    type UploadFileFut = Ready<Result<u64, AgentError>>;
+    fn upload_file(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        sha256: String,
+        chunk: FileChunk,
+    ) -> Self::UploadFileFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, true);
+
+        ready(
+            self.state
+                .allowed_roots()
+                .and_then(|roots| upload_chunk(&roots, &path, &sha256, &chunk)),
+        )
+    }
+
+    type UploadFileFinishFut = BoxFuture<'static, Result<(), AgentError>>;
+    fn upload_file_finish(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        info: FileTransferInfo,
+    ) -> Self::UploadFileFinishFut {
+        check_env_id!(env_id, boxed);
+        check_read_only!(self, boxed);
+        let path = jail_path!(self, path, true, boxed);
+
+        // Verifying the upload hashes the whole file
+        spawn_request(move || {
+            self.state
+                .allowed_roots()
+                .and_then(|roots| upload_finish(&roots, &path, &info))
+        })
+    }
+
+    type DownloadFileInfoFut = BoxFuture<'static, Result<FileTransferInfo, AgentError>>;
+    fn download_file_info(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+    ) -> Self::DownloadFileInfoFut {
+        check_env_id!(env_id, boxed);
+        let path = jail_path!(self, path, true, boxed);
+
+        spawn_request(move || download_info(&path))
+    }
+
+    type DownloadFileFut = Ready<Result<FileChunk, AgentError>>;
+    fn download_file(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        offset: u64,
+        length: u64,
+    ) -> Self::DownloadFileFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(download_chunk(&path, offset, length))
+    }
+
+    type ExtractArchiveFut = BoxFuture<'static, Result<(), AgentError>>;
+    fn extract_archive(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        archive_path: String,
+        dest_dir: String,
+        format: Option<ArchiveFormat>,
+    ) -> Self::ExtractArchiveFut {
+        check_env_id!(env_id, boxed);
+        check_read_only!(self, boxed);
+        let archive_path = jail_path!(self, archive_path, true, boxed);
+        let dest_dir = jail_path!(self, dest_dir, true, boxed);
+
+        spawn_request(move || extract_archive(&archive_path, &dest_dir, format))
+    }
+
+    type PackArchiveFut = BoxFuture<'static, Result<(), AgentError>>;
+    fn pack_archive(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        src_dir: String,
+        archive_path: String,
+        format: ArchiveFormat,
+    ) -> Self::PackArchiveFut {
+        check_env_id!(env_id, boxed);
+        check_read_only!(self, boxed);
+        let src_dir = jail_path!(self, src_dir, true, boxed);
+        let archive_path = jail_path!(self, archive_path, true, boxed);
+
+        spawn_request(move || pack_archive(&src_dir, &archive_path, format))
+    }
+
+    type FileHashFut = BoxFuture<'static, Result<String, AgentError>>;
+    fn file_hash(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        file: FileRef,
+        algorithm: HashAlgorithm,
+        range: Option<ByteRange>,
+    ) -> Self::FileHashFut {
+        check_env_id!(env_id, boxed);
+        let file = match file {
+            FileRef::Path(path) => {
+                let path = jail_path!(self, path, true, boxed);
+                return spawn_request(move || hash_file(&File::open(path)?, algorithm, range));
+            }
+            // The open file is duplicated, so that it isn't locked for as long as hashing takes.
+            // Hashing reads at offsets, so the position they share doesn't move.
+            FileRef::File(fd) => match self
+                .state
+                .do_mut_operation(&fd, |file| file.try_clone())
+                .and_then(|r| r.map_err(AgentError::from))
+            {
+                Ok(file) => file,
+                Err(e) => return Box::pin(ready(Err(e))),
+            },
+        };
+
+        spawn_request(move || hash_file(&file, algorithm, range))
+    }
+
+    type SearchFut = BoxFuture<'static, Result<SearchResult, AgentError>>;
+    fn search(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        pattern: MatchPattern,
+        context: u32,
+        limits: SearchLimits,
+    ) -> Self::SearchFut {
+        check_env_id!(env_id, boxed);
+        let path = jail_path!(self, path, true, boxed);
+
+        // Searching a large tree takes a while
+        spawn_request(move || search(&path, &pattern, context, limits))
+    }
+
+    type BlobMissingFut = Ready<Result<Vec<String>, AgentError>>;
+    fn blob_missing(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        hashes: Vec<String>,
+    ) -> Self::BlobMissingFut {
+        check_env_id!(env_id);
+
+        ready(self.state.blob_store().missing(hashes))
+    }
+
+    type BlobUploadFut = Ready<Result<u64, AgentError>>;
+    fn blob_upload(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        hash: String,
+        chunk: FileChunk,
+    ) -> Self::BlobUploadFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+
+        ready(self.state.blob_store().upload(&hash, &chunk))
+    }
+
+    type BlobUploadFinishFut = Ready<Result<(), AgentError>>;
+    fn blob_upload_finish(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        hash: String,
+        size: u64,
+    ) -> Self::BlobUploadFinishFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+
+        ready(self.state.blob_store().finish(&hash, size))
+    }
+
+    type BlobMaterializeFut = Ready<Result<(), AgentError>>;
+    fn blob_materialize(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        hash: String,
+        path: String,
+        hardlink: bool,
+        mode: Option<u32>,
+    ) -> Self::BlobMaterializeFut {
+        check_env_id!(env_id);
+        check_read_only!(self);
+        let path = jail_path!(self, path, true);
+
+        ready(
+            self.state
+                .blob_store()
+                .materialize(&hash, &path, hardlink, mode),
+        )
+    }
+
+    type RunBlobCommandFut = Ready<Result<ProcessId, AgentError>>;
+    fn run_blob_command(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        hash: String,
+        config: RemotePOpenConfig,
+    ) -> Self::RunBlobCommandFut {
+        check_env_id!(env_id);
+
+        ready(self.state.run_blob_command(&hash, config))
+    }
+
+    type WatchPathFut = Ready<Result<WatchId, AgentError>>;
+    fn watch_path(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        path: String,
+        recursive: bool,
+        events: Vec<WatchEventKind>,
+    ) -> Self::WatchPathFut {
+        check_env_id!(env_id);
+        let path = jail_path!(self, path, true);
+
+        ready(self.state.watch_path(&path, recursive, &events))
+    }
+
+    type WatchEventsFut = BoxFuture<'static, Result<Vec<WatchEvent>, AgentError>>;
+    fn watch_events(
+        self,
+        _: Context,
+        env_id: EnvironmentId,
+        watch_id: WatchId,
+        max_events: u32,
+        timeout: Option<f64>,
+    ) -> Self::WatchEventsFut {
+        check_env_id!(env_id, boxed);
+        let timeout = timeout_duration(timeout);
+
+        spawn_cancellable(move |cancelled| {
+            self.state
+                .watch_events(&watch_id, max_events as usize, timeout, cancelled)
+        })
+    }
+
+    type UnwatchFut = Ready<Result<(), AgentError>>;
+    fn unwatch(self, _: Context, env_id: EnvironmentId, watch_id: WatchId) -> Self::UnwatchFut {
+        check_env_id!(env_id);
+
+        ready(self.state.unwatch(&watch_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BhAgentConfig;
+    use crate::util::TestDir;
+    use std::path::PathBuf;
+    use tarpc::context;
+
+    fn read_only_server() -> BhAgentServer {
+        let config = BhAgentConfig {
+            read_only: true,
+            allowed_commands: vec![PathBuf::from("/bin/true")],
+            ..Default::default()
+        };
+        BhAgentServer::new(
+            "127.0.0.1:1".parse().unwrap(),
+            Arc::new(BhAgentGlobalState::new(config)),
+        )
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = TestDir::new("server_read_only");
+        let file = dir.join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let file = file.to_str().unwrap().to_string();
+        let server = read_only_server();
+        let open = |mode| {
+            server.clone().file_open(
+                context::current(),
+                0,
+                file.clone(),
+                mode,
+                FileOpenType::Binary,
+            )
+        };
+
+        assert!(matches!(
+            open(FileOpenMode::Write).into_inner(),
+            Err(AgentError::ReadOnly)
+        ));
+        let fd = open(FileOpenMode::Read).into_inner().unwrap();
+        assert!(matches!(
+            server
+                .clone()
+                .file_write(context::current(), 0, fd, b"x".to_vec())
+                .into_inner(),
+            Err(AgentError::ReadOnly)
+        ));
+        assert_eq!(
+            server
+                .clone()
+                .file_read(context::current(), 0, fd, 4)
+                .into_inner()
+                .unwrap(),
+            b"data"
+        );
+        assert!(matches!(
+            server
+                .clone()
+                .remove(context::current(), 0, file.clone(), false)
+                .into_inner(),
+            Err(AgentError::ReadOnly)
+        ));
+        assert_eq!(std::fs::read(&file).unwrap(), b"data");
+
+        let run = |argv: &str, record: Option<String>| {
+            server
+                .clone()
+                .run_command(
+                    context::current(),
+                    0,
+                    RemotePOpenConfig {
+                        argv: vec![argv.to_string()],
+                        record,
+                        ..Default::default()
+                    },
+                )
+                .into_inner()
+        };
+        assert!(matches!(run("false", None), Err(AgentError::ReadOnly)));
+        assert!(matches!(
+            run(
+                "/bin/true",
+                Some(dir.join("record").to_str().unwrap().to_string())
+            ),
+            Err(AgentError::ReadOnly)
+        ));
+        run("/bin/true", None).unwrap();
+
+        let chunk = FileChunk {
+            offset: 0,
+            data: b"data".to_vec(),
+        };
+        let hash = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7";
+        assert!(matches!(
+            server
+                .clone()
+                .blob_upload(context::current(), 0, hash.to_string(), chunk)
+                .into_inner(),
+            Err(AgentError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_seek_and_truncate() {
+        let dir = TestDir::new("server_seek");
+        let server = BhAgentServer::new(
+            "127.0.0.1:1".parse().unwrap(),
+            Arc::new(BhAgentGlobalState::default()),
+        );
+        let open = |path: PathBuf| {
+            server
+                .clone()
+                .file_open(
+                    context::current(),
+                    0,
+                    path.to_str().unwrap().to_string(),
+                    FileOpenMode::ReadUpdate,
+                    FileOpenType::Binary,
+                )
+                .into_inner()
+                .unwrap()
+        };
+        let path = dir.join("sparse");
+        std::fs::write(&path, b"data").unwrap();
+        let fd = open(path);
+        let seek = |offset, whence| {
+            server
+                .clone()
+                .file_seek(context::current(), 0, fd, offset, whence)
+                .into_inner()
+        };
+        let tell = || {
+            server
+                .clone()
+                .file_tell(context::current(), 0, fd)
+                .into_inner()
+                .unwrap()
+        };
+        let read = |num_bytes| {
+            server
+                .clone()
+                .file_read(context::current(), 0, fd, num_bytes)
+                .into_inner()
+                .unwrap()
+        };
+        let truncate = |size| {
+            server
+                .clone()
+                .file_truncate(context::current(), 0, fd, size)
+                .into_inner()
+                .unwrap()
+        };
+
+        // Offsets past 4 GiB survive the round trip, leaving a hole in the file
+        let far = 5 << 30;
+        assert_eq!(seek(far as i64, 0).unwrap(), far);
+        server
+            .clone()
+            .file_write(context::current(), 0, fd, b"tail".to_vec())
+            .into_inner()
+            .unwrap();
+        assert_eq!(tell(), far + 4);
+        assert_eq!(seek(-4, 2).unwrap(), far);
+        assert_eq!(read(4), b"tail");
+        assert_eq!(seek(-2, 1).unwrap(), far + 2);
+        assert_eq!(read(4), b"il");
+        assert!(matches!(seek(-1, 0), Err(AgentError::InvalidOffset)));
+        assert!(matches!(seek(0, 3), Err(AgentError::InvalidSeekWhence)));
+
+        assert_eq!(truncate(Some(far + 2)), far + 2);
+        assert_eq!(seek(0, 2).unwrap(), far + 2);
+
+        // Without a size, truncation cuts the file at the current position
+        assert_eq!(seek(2, 0).unwrap(), 2);
+        assert_eq!(truncate(None), 2);
+        assert!(read(4).is_empty());
+        assert_eq!(seek(0, 0).unwrap(), 0);
+        assert_eq!(read(4), b"da");
+
+        let is_seekable = |fd| {
+            server
+                .clone()
+                .file_is_seekable(context::current(), 0, fd)
+                .into_inner()
+                .unwrap()
+        };
+        assert!(is_seekable(fd));
+        let fifo = dir.join("fifo");
+        let fifo_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o600) }, 0);
+        assert!(!is_seekable(open(fifo)));
+    }
+
+    #[test]
+    fn test_links_and_ownership() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TestDir::new("server_links");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let outside = dir.join("outside");
+        std::fs::write(&outside, b"secret").unwrap();
+        std::fs::write(root.join("file"), b"data").unwrap();
+        let config = BhAgentConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        };
+        let server = BhAgentServer::new(
+            "127.0.0.1:1".parse().unwrap(),
+            Arc::new(BhAgentGlobalState::new(config)),
+        );
+        let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+        let outside_path = outside.to_str().unwrap().to_string();
+        let mode = |name: &str| std::fs::metadata(root.join(name)).unwrap().mode() & 0o777;
+
+        let symlink = |target: &str, link: &str| {
+            server
+                .clone()
+                .symlink(context::current(), 0, target.to_string(), path(link))
+                .into_inner()
+        };
+        symlink("file", "link").unwrap();
+        assert!(matches!(
+            symlink(&outside_path, "escape"),
+            Err(AgentError::PathNotAllowed)
+        ));
+        assert!(matches!(
+            symlink("../outside", "escape"),
+            Err(AgentError::PathNotAllowed)
+        ));
+        assert!(!root.join("escape").exists());
+
+        // The target is stored as given, relative or not
+        let readlink = |path: String| {
+            server
+                .clone()
+                .readlink(context::current(), 0, path)
+                .into_inner()
+        };
+        assert_eq!(readlink(path("link")).unwrap(), "file");
+        assert!(matches!(
+            readlink(outside_path.clone()),
+            Err(AgentError::PathNotAllowed)
+        ));
+        // A link inside the roots can be read even when it points outside of them
+        std::os::unix::fs::symlink(&outside, root.join("planted")).unwrap();
+        assert_eq!(readlink(path("planted")).unwrap(), outside_path);
+
+        let chmod = |path: String, mode| {
+            server
+                .clone()
+                .chmod(context::current(), 0, path, mode)
+                .into_inner()
+        };
+        let outside_mode = std::fs::metadata(&outside).unwrap().mode();
+        chmod(path("file"), 0o640).unwrap();
+        assert_eq!(mode("file"), 0o640);
+        chmod(path("link"), 0o600).unwrap();
+        assert_eq!(mode("file"), 0o600);
+        assert!(matches!(
+            chmod(path("planted"), 0o777),
+            Err(AgentError::PathNotAllowed)
+        ));
+        assert_eq!(std::fs::metadata(&outside).unwrap().mode(), outside_mode);
+
+        let chown = |path: String, uid, gid, follow_symlinks| {
+            server
+                .clone()
+                .chown(context::current(), 0, path, uid, gid, follow_symlinks)
+                .into_inner()
+        };
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+        chown(path("file"), Some(uid), Some(gid), true).unwrap();
+        chown(path("link"), None, Some(gid), true).unwrap();
+        // Not following the link changes the link itself, wherever it points
+        chown(path("planted"), Some(uid), None, false).unwrap();
+        assert!(matches!(
+            chown(path("planted"), Some(uid), None, true),
+            Err(AgentError::PathNotAllowed)
+        ));
+        assert!(matches!(
+            chown(path("missing"), None, None, true),
+            Err(AgentError::NotFound)
+        ));
+
+        let hardlink = |target: String, link: &str| {
+            server
+                .clone()
+                .hardlink(context::current(), 0, target, path(link))
+                .into_inner()
+        };
+        hardlink(path("file"), "hard").unwrap();
+        assert_eq!(
+            std::fs::metadata(root.join("hard")).unwrap().ino(),
+            std::fs::metadata(root.join("file")).unwrap().ino()
+        );
+        assert!(matches!(
+            hardlink(outside_path.clone(), "hard_escape"),
+            Err(AgentError::PathNotAllowed)
+        ));
+        // Hard linking a symlink links the symlink, not what it points to
+        hardlink(path("planted"), "hard_planted").unwrap();
+        assert_eq!(readlink(path("hard_planted")).unwrap(), outside_path);
+    }
 }
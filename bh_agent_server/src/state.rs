@@ -1,35 +1,190 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use subprocess::{Popen, PopenConfig};
 
 use bh_agent_common::AgentError::{
-    InvalidFileDescriptor, InvalidProcessId, IoError, ProcessStartFailure,
+    InvalidFileDescriptor, InvalidProcessChannel, InvalidProcessId, InvalidTempName,
+    InvalidWatchId, IoError, PathNotAllowed, PermissionDenied, ProcessChannelNotPiped,
+    ProcessStartFailure, ReadOnly,
 };
 use bh_agent_common::{
-    AgentError, FileId, FileOpenMode, FileOpenType, ProcessChannel, ProcessId, Redirection,
-    RemotePOpenConfig,
+    AgentError, CaptureTranscript, CollectedOutput, DetachedProcessInfo, EnvironmentId,
+    ExecutableSource, ExitStatus, ExpectMatch, FileId, FileOpenMode, FileOpenType, ProcessChannel,
+    ProcessId, ProcessTreeEntry, Redirection, RemotePOpenConfig, ReplayReport, ReplayTiming,
+    WatchEvent, WatchEventKind, WatchId,
 };
 
+use crate::blob_store::BlobStore;
+use crate::global_state::BhAgentGlobalState;
+use crate::process;
+use crate::process::ManagedProcess;
+use crate::recording::{read_transcript, Recorder};
+use crate::replay::replay;
+use crate::util::{
+    expect, jail_path, make_temp, memfd_from_bytes, memfd_from_file, open_jailed, resolve_path,
+    Matcher, TextReader,
+};
+use crate::watch::Watcher;
+
+fn create_popen(config: &RemotePOpenConfig) -> Result<Popen, AgentError> {
+    let redirection = |r: Redirection| match r {
+        Redirection::None => subprocess::Redirection::None,
+        Redirection::Save | Redirection::Capture => subprocess::Redirection::Pipe,
+    };
+    let mut popenconfig = PopenConfig {
+        stdin: redirection(config.stdin),
+        stdout: redirection(config.stdout),
+        stderr: redirection(config.stderr),
+        detached: config.detached,
+        executable: config.executable.clone().map(|s| s.into()),
+        env: config.env.clone().map(|v| {
+            v.iter()
+                .map(|t| (t.0.clone().into(), t.1.clone().into()))
+                .collect()
+        }),
+        cwd: config.cwd.clone().map(|s| s.into()),
+        ..PopenConfig::default()
+    };
+    #[cfg(unix)]
+    {
+        popenconfig.setuid = config.setuid.or(popenconfig.setuid);
+        popenconfig.setgid = config.setgid.or(popenconfig.setgid);
+        popenconfig.setpgid = config.setpgid;
+    }
+
+    Popen::create(
+        config
+            .argv
+            .iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>()
+            .as_slice(),
+        popenconfig,
+    )
+    .map_err(|_| ProcessStartFailure)
+}
+
+// Finds the program a process config would run, looking bare names up in the agent's own PATH. The
+// last component isn't resolved, so a symlink to a program is a different program.
+fn find_program(config: &RemotePOpenConfig) -> Option<PathBuf> {
+    let program = config
+        .executable
+        .as_deref()
+        .or(config.argv.first().map(String::as_str))?;
+    if program.contains('/') {
+        return resolve_path(Path::new(program), false).ok();
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| {
+            path.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .and_then(|path| resolve_path(&path, false).ok())
+}
+
+// How often a process that's being waited for checks whether its request was cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Waits a slice at a time, so that a cancelled request is noticed. The process is killed on
+// timeout or cancellation.
+fn collect_output(
+    proc: &mut Popen,
+    stdin: Vec<u8>,
+    timeout: Option<Duration>,
+    cancelled: &AtomicBool,
+) -> Result<CollectedOutput, AgentError> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let next_wait = || {
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        match cancelled.load(Ordering::Acquire) || remaining == Some(Duration::ZERO) {
+            true => None,
+            false => {
+                Some(remaining.map_or(CANCEL_CHECK_INTERVAL, |r| r.min(CANCEL_CHECK_INTERVAL)))
+            }
+        }
+    };
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut communicator = proc.communicate_start(Some(stdin));
+    let mut timed_out = loop {
+        let Some(wait) = next_wait() else {
+            break true;
+        };
+        communicator = communicator.limit_time(wait);
+        let (output, done) = match communicator.read() {
+            Ok(output) => (output, true),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (e.capture, false),
+            Err(_) => return Err(IoError),
+        };
+        stdout.extend(output.0.unwrap_or_default());
+        stderr.extend(output.1.unwrap_or_default());
+        if done {
+            break false;
+        }
+    };
+    drop(communicator);
+
+    let mut exit_status = None;
+    while !timed_out && exit_status.is_none() {
+        match next_wait() {
+            Some(wait) => exit_status = proc.wait_timeout(wait).map_err(|_| IoError)?,
+            None => timed_out = true,
+        }
+    }
+    if exit_status.is_none() {
+        proc.kill().map_err(|_| IoError)?;
+        exit_status = Some(proc.wait().map_err(|_| IoError)?);
+    }
+
+    Ok(CollectedOutput {
+        stdout,
+        stderr,
+        exit_status: exit_status.map_or(ExitStatus::Undetermined, process::exit_status),
+        timed_out,
+    })
+}
+
 // TODO: Someday a simple in-memory key value store might be a good idea
 pub struct BhAgentState {
+    peer_addr: SocketAddr,
+    global: Arc<BhAgentGlobalState>,
+
     files: RwLock<HashMap<FileId, Arc<RwLock<File>>>>,
     file_modes: RwLock<HashMap<FileId, FileOpenMode>>,
     file_types: RwLock<HashMap<FileId, FileOpenType>>,
-    processes: RwLock<HashMap<ProcessId, Arc<RwLock<Popen>>>>,
+    processes: RwLock<HashMap<ProcessId, Arc<ManagedProcess>>>,
     proc_stdin_ids: RwLock<HashMap<FileId, ProcessId>>,
     proc_stdout_ids: RwLock<HashMap<FileId, ProcessId>>,
     proc_stderr_ids: RwLock<HashMap<FileId, ProcessId>>,
+    watches: RwLock<HashMap<WatchId, Arc<Watcher>>>,
+    session_temp_dirs: RwLock<HashMap<EnvironmentId, PathBuf>>,
+    // Bytes read ahead from files that can't seek back to give them up
+    unread: RwLock<HashMap<FileId, Vec<u8>>>,
+    // Text files whose last read ended a line on a \r, so a \n read next belongs to that line
+    pending_cr: RwLock<HashSet<FileId>>,
 
     next_file_id: RwLock<FileId>,
-    next_process_id: RwLock<ProcessId>,
+    next_watch_id: RwLock<WatchId>,
 }
 
 impl BhAgentState {
-    pub fn new() -> BhAgentState {
+    pub fn new(peer_addr: SocketAddr, global: Arc<BhAgentGlobalState>) -> BhAgentState {
         Self {
+            peer_addr,
+            global,
+
             files: RwLock::new(HashMap::new()),
             file_modes: RwLock::new(HashMap::new()),
             file_types: RwLock::new(HashMap::new()),
@@ -37,9 +192,13 @@ impl BhAgentState {
             proc_stdin_ids: RwLock::new(HashMap::new()),
             proc_stdout_ids: RwLock::new(HashMap::new()),
             proc_stderr_ids: RwLock::new(HashMap::new()),
+            watches: RwLock::new(HashMap::new()),
+            session_temp_dirs: RwLock::new(HashMap::new()),
+            unread: RwLock::new(HashMap::new()),
+            pending_cr: RwLock::new(HashSet::new()),
 
             next_file_id: RwLock::new(0),
-            next_process_id: RwLock::new(0),
+            next_watch_id: RwLock::new(0),
         }
     }
 
@@ -50,33 +209,25 @@ impl BhAgentState {
         Ok(file_id)
     }
 
-    fn take_proc_id(&self) -> Result<ProcessId, AgentError> {
-        let mut next_process_id = self.next_process_id.write()?;
-        let process_id = *next_process_id;
-        *next_process_id += 1;
-        Ok(process_id)
-    }
-
     pub fn file_has_any_mode(
         &self,
         fd: &FileId,
-        modes: &Vec<FileOpenMode>,
+        modes: &[FileOpenMode],
     ) -> Result<bool, AgentError> {
         Ok(modes.contains(
             self.file_modes
                 .read()?
-                .get(&fd)
+                .get(fd)
                 .ok_or(InvalidFileDescriptor)?,
         ))
     }
 
     pub fn file_type(&self, fd: &FileId) -> Result<FileOpenType, AgentError> {
-        Ok(self
-            .file_types
+        self.file_types
             .read()?
-            .get(&fd)
+            .get(fd)
             .ok_or(InvalidFileDescriptor)
-            .and_then(|t| Ok(t.clone()))?)
+            .copied()
     }
 
     pub fn open_path(
@@ -85,19 +236,30 @@ impl BhAgentState {
         mode: FileOpenMode,
         type_: FileOpenType,
     ) -> Result<FileId, AgentError> {
-        let mut open_opts = OpenOptions::new();
-        match mode {
-            FileOpenMode::Read => open_opts.read(true),
-            FileOpenMode::Write => open_opts.write(true).create(true),
-            FileOpenMode::ExclusiveWrite => open_opts.write(true).create_new(true),
-            FileOpenMode::Append => open_opts.append(true),
-            FileOpenMode::Update => open_opts.read(true).write(true),
+        if self.read_only() && mode != FileOpenMode::Read {
+            return Err(ReadOnly);
+        }
+        let flags = match mode {
+            FileOpenMode::Read => libc::O_RDONLY,
+            FileOpenMode::ReadUpdate => libc::O_RDWR,
+            FileOpenMode::Write => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            FileOpenMode::WriteUpdate => libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+            FileOpenMode::ExclusiveWrite => libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+            FileOpenMode::ExclusiveWriteUpdate => libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            FileOpenMode::Append => libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT,
+            FileOpenMode::AppendUpdate => libc::O_RDWR | libc::O_APPEND | libc::O_CREAT,
         };
-        let file = open_opts.open(&path).map_err(|e| {
-            eprintln!("Path: {}", path);
-            eprintln!("Error opening file: {}", e);
-            IoError
-        })?;
+        let roots = self.allowed_roots()?;
+        // A read-only agent leaves access times alone where it can. O_NOATIME is only allowed on
+        // files the agent owns, or with CAP_FOWNER, so it's dropped when it's refused.
+        let result = match self.read_only() {
+            true => match open_jailed(&roots, &path, flags | libc::O_NOATIME) {
+                Err(PermissionDenied) => open_jailed(&roots, &path, flags),
+                result => result,
+            },
+            false => open_jailed(&roots, &path, flags),
+        };
+        let file = result?;
         let file_id = self.take_file_id()?;
         self.files
             .write()?
@@ -107,70 +269,213 @@ impl BhAgentState {
         Ok(file_id)
     }
 
-    pub fn run_command(&self, config: RemotePOpenConfig) -> Result<ProcessId, AgentError> {
-        let mut popenconfig = PopenConfig {
-            stdin: match config.stdin {
-                Redirection::None => subprocess::Redirection::None,
-                Redirection::Save => subprocess::Redirection::Pipe,
-            },
-            stdout: match config.stdout {
-                Redirection::None => subprocess::Redirection::None,
-                Redirection::Save => subprocess::Redirection::Pipe,
-            },
-            stderr: match config.stderr {
-                Redirection::None => subprocess::Redirection::None,
-                Redirection::Save => subprocess::Redirection::Pipe,
-            },
-            detached: false,
-            executable: config.executable.map(|s| s.into()),
-            env: config.env.map(|v| {
-                v.iter()
-                    .map(|t| (t.0.clone().into(), t.1.clone().into()))
-                    .collect()
-            }),
-            cwd: config.cwd.map(|s| s.into()),
-            ..PopenConfig::default()
+    pub fn run_command(&self, mut config: RemotePOpenConfig) -> Result<ProcessId, AgentError> {
+        if config.stdin == Redirection::Capture {
+            return Err(InvalidProcessChannel);
+        }
+        self.check_popen_config(&mut config)?;
+
+        let proc_id = self.global.take_proc_id()?;
+        let start = Instant::now();
+        let recorder = match &config.record {
+            Some(path) => Some(Arc::new(Recorder::create(path, start)?)),
+            None => None,
         };
-        #[cfg(unix)]
-        {
-            popenconfig.setuid = config.setuid.or(popenconfig.setuid);
-            popenconfig.setgid = config.setuid.or(popenconfig.setgid);
-            popenconfig.setpgid = config.setuid.or(popenconfig.setpgid);
+        let proc = self
+            .global
+            .process_tracker
+            .spawn(proc_id, || create_popen(&config))?;
+
+        let proc = Arc::new(ManagedProcess::new(proc, &config, start, recorder)?);
+        if config.detached {
+            self.global.add_detached_process(
+                proc_id,
+                proc.clone(),
+                config.argv,
+                self.peer_addr.to_string(),
+            )?;
         }
+        self.add_process(proc_id, proc)?;
 
-        let proc = Popen::create(
-            config
-                .argv
-                .iter()
-                .map(|s| OsStr::new(s))
-                .collect::<Vec<_>>()
-                .as_slice(),
-            popenconfig,
-        )
-        .map_err(|_| ProcessStartFailure)?;
+        Ok(proc_id)
+    }
+
+    pub fn run_memfd_command(
+        &self,
+        executable: ExecutableSource,
+        mut config: RemotePOpenConfig,
+    ) -> Result<ProcessId, AgentError> {
+        let name = config.argv.first().map_or("bh_agent_exec", |s| s.as_str());
+        let memfd = match &executable {
+            ExecutableSource::Bytes(data) => memfd_from_bytes(name, data).map_err(|_| IoError)?,
+            ExecutableSource::File(fd) => self
+                .do_mut_operation(fd, |file| memfd_from_file(name, file))?
+                .map_err(|_| IoError)?,
+        };
+        // The memfd only needs to stay open until the child has exec'd, which Popen::create
+        // waits for
+        config.executable = Some(format!("/proc/self/fd/{}", memfd.as_raw_fd()));
+        self.run_command(config)
+    }
+
+    pub fn blob_store(&self) -> &BlobStore {
+        &self.global.blob_store
+    }
+
+    pub fn run_blob_command(
+        &self,
+        hash: &str,
+        mut config: RemotePOpenConfig,
+    ) -> Result<ProcessId, AgentError> {
+        let blob = self.global.blob_store.get(hash)?;
+        config.executable = Some(blob.to_string_lossy().into_owned());
+        self.run_command(config)
+    }
 
-        let proc_id = self.take_proc_id()?;
+    /// Runs a process to completion, feeding it stdin and collecting all of its output. If the
+    /// timeout expires the process is killed, and whatever output it produced is returned. Any
+    /// descendants still running once it finishes are killed as well.
+    pub fn run_and_collect(
+        &self,
+        mut config: RemotePOpenConfig,
+        stdin: Vec<u8>,
+        timeout: Option<Duration>,
+        cancelled: &AtomicBool,
+    ) -> Result<CollectedOutput, AgentError> {
+        self.check_popen_config(&mut config)?;
+        config.stdin = Redirection::Save;
+        config.stdout = Redirection::Save;
+        config.stderr = Redirection::Save;
+        config.detached = false;
+
+        let proc_id = self.global.take_proc_id()?;
+        let tracker = &self.global.process_tracker;
+        let mut proc = tracker.spawn(proc_id, || create_popen(&config))?;
+        let result = collect_output(&mut proc, stdin, timeout, cancelled);
+        tracker.kill_descendants(&proc_id)?;
+        tracker.untrack(&proc_id)?;
+        result
+    }
+
+    /// Replays the stdin side of a recorded transcript against a new run of a process, and reports
+    /// where its output diverged from the recording.
+    pub fn replay_transcript(
+        &self,
+        transcript_path: &str,
+        mut config: RemotePOpenConfig,
+        timing: ReplayTiming,
+        timeout: Option<Duration>,
+    ) -> Result<ReplayReport, AgentError> {
+        self.check_popen_config(&mut config)?;
+        let transcript = read_transcript(&self.jail_path(transcript_path, true)?)?;
+        config.stdin = Redirection::Save;
+        config.stdout = Redirection::Save;
+        config.stderr = Redirection::Save;
+        config.detached = false;
+
+        let proc_id = self.global.take_proc_id()?;
+        let tracker = &self.global.process_tracker;
+        let proc = tracker.spawn(proc_id, || create_popen(&config))?;
+        let result = replay(proc, &transcript, timing, timeout);
+        tracker.kill_descendants(&proc_id)?;
+        tracker.untrack(&proc_id)?;
+        result
+    }
 
+    pub fn process_kill(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        let proc = self.get_process(proc_id)?;
+        self.kill_process_tree(proc_id, &proc)
+    }
+
+    fn kill_process_tree(
+        &self,
+        proc_id: &ProcessId,
+        proc: &ManagedProcess,
+    ) -> Result<(), AgentError> {
+        self.global.process_tracker.kill_descendants(proc_id)?;
+        let mut popen = proc.popen.write()?;
+        if popen.poll().is_none() {
+            popen.kill().map_err(|_| IoError)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_process_tree(
+        &self,
+        proc_id: &ProcessId,
+    ) -> Result<Vec<ProcessTreeEntry>, AgentError> {
+        self.get_process(proc_id)?;
+        self.global.process_tracker.tree(proc_id)
+    }
+
+    pub fn attach_process(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        if self.processes.read()?.contains_key(proc_id) {
+            return Ok(());
+        }
+        self.add_process(*proc_id, self.global.get_detached_process(proc_id)?)
+    }
+
+    // Detached processes stay registered with the agent until they're forgotten. A process that's
+    // still running is killed with its descendants first, and then reaped. Temporary directories
+    // that were kept for it are removed as well.
+    pub fn forget_process(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        let (proc, temp_dirs) = self.global.remove_detached_process(proc_id)?;
+        self.kill_process_tree(proc_id, &proc)?;
+        proc.popen.write()?.wait().map_err(|_| IoError)?;
+        self.global.process_tracker.untrack(proc_id)?;
+        for dir in temp_dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        self.remove_process(proc_id)
+    }
+
+    fn add_process(&self, proc_id: ProcessId, proc: Arc<ManagedProcess>) -> Result<(), AgentError> {
         // Stick the process channels into the file map
         if proc.stdin.is_some() {
             let file_id = self.take_file_id()?;
             self.proc_stdin_ids.write()?.insert(file_id, proc_id);
+            self.file_types
+                .write()?
+                .insert(file_id, FileOpenType::Binary);
         }
         if proc.stdout.is_some() {
             let file_id = self.take_file_id()?;
             self.proc_stdout_ids.write()?.insert(file_id, proc_id);
+            self.file_types
+                .write()?
+                .insert(file_id, FileOpenType::Binary);
         }
         if proc.stderr.is_some() {
             let file_id = self.take_file_id()?;
-            self.proc_stdout_ids.write()?.insert(file_id, proc_id);
+            self.proc_stderr_ids.write()?.insert(file_id, proc_id);
+            self.file_types
+                .write()?
+                .insert(file_id, FileOpenType::Binary);
         }
 
         // Move the proc to the process map
-        self.processes
-            .write()?
-            .insert(proc_id, Arc::new(RwLock::new(proc)));
+        self.processes.write()?.insert(proc_id, proc);
 
-        Ok(proc_id)
+        Ok(())
+    }
+
+    fn remove_process(&self, proc_id: &ProcessId) -> Result<(), AgentError> {
+        for ids in [
+            &self.proc_stdin_ids,
+            &self.proc_stdout_ids,
+            &self.proc_stderr_ids,
+        ] {
+            let mut ids = ids.write()?;
+            let mut file_types = self.file_types.write()?;
+            ids.retain(|file_id, pid| {
+                if pid == proc_id {
+                    file_types.remove(file_id);
+                }
+                pid != proc_id
+            });
+        }
+        self.processes.write()?.remove(proc_id);
+        Ok(())
     }
 
     pub fn get_process_channel(
@@ -178,34 +483,332 @@ impl BhAgentState {
         proc_id: &ProcessId,
         channel: ProcessChannel,
     ) -> Result<FileId, AgentError> {
+        if !self.processes.read()?.contains_key(proc_id) {
+            return Err(InvalidProcessId);
+        }
         match channel {
             ProcessChannel::Stdin => &self.proc_stdin_ids,
             ProcessChannel::Stdout => &self.proc_stdout_ids,
             ProcessChannel::Stderr => &self.proc_stderr_ids,
         }
         .read()?
-        .get(&proc_id)
-        .map(|i| i.clone())
-        .ok_or(InvalidProcessId)
+        .iter()
+        .find(|(_, pid)| *pid == proc_id)
+        .map(|(file_id, _)| *file_id)
+        .ok_or(ProcessChannelNotPiped)
+    }
+
+    fn get_process(&self, proc_id: &ProcessId) -> Result<Arc<ManagedProcess>, AgentError> {
+        self.processes
+            .read()?
+            .get(proc_id)
+            .cloned()
+            .ok_or(InvalidProcessId)
+    }
+
+    pub fn process_expect(
+        &self,
+        proc_id: &ProcessId,
+        channel: ProcessChannel,
+        matcher: &Matcher,
+        timeout: Option<Duration>,
+        cancelled: &AtomicBool,
+    ) -> Result<ExpectMatch, AgentError> {
+        let proc = self.get_process(proc_id)?;
+        expect(proc.output_channel(channel)?, matcher, timeout, cancelled)
+    }
+
+    pub fn get_process_capture(
+        &self,
+        proc_id: &ProcessId,
+        start_seq: u64,
+    ) -> Result<CaptureTranscript, AgentError> {
+        self.get_process(proc_id)?
+            .capture
+            .as_ref()
+            .ok_or(ProcessChannelNotPiped)?
+            .transcript(start_seq)
+    }
+
+    pub fn process_sendline(&self, proc_id: &ProcessId, data: &[u8]) -> Result<(), AgentError> {
+        let proc = self.get_process(proc_id)?;
+        let mut stdin = proc.stdin.as_ref().ok_or(ProcessChannelNotPiped)?.write()?;
+        stdin
+            .write_all(data)
+            .and_then(|_| stdin.write_all(b"\n"))
+            .and_then(|_| stdin.flush())
+            .map_err(|_| IoError)
+    }
+
+    /// If fd is a process output channel with output buffered by the agent, returns up to n bytes
+    /// of that output. Buffered output must be consumed before reading from the pipe again.
+    pub fn take_buffered_output(
+        &self,
+        fd: &FileId,
+        n: usize,
+    ) -> Result<Option<Vec<u8>>, AgentError> {
+        {
+            let mut unread = self.unread.write()?;
+            if let Some(pending) = unread.get_mut(fd) {
+                let taken = pending.drain(..n.min(pending.len())).collect();
+                if pending.is_empty() {
+                    unread.remove(fd);
+                }
+                return Ok(Some(taken));
+            }
+        }
+        let (proc_id, channel) = match self.proc_stdout_ids.read()?.get(fd) {
+            Some(pid) => (*pid, ProcessChannel::Stdout),
+            None => match self.proc_stderr_ids.read()?.get(fd) {
+                Some(pid) => (*pid, ProcessChannel::Stderr),
+                None => return Ok(None),
+            },
+        };
+        let proc = self.get_process(&proc_id)?;
+        let mut output = proc.output_channel(channel)?.write()?;
+        Ok(output.take_buffered(n))
+    }
+
+    // Read ahead bytes go back into the file if it can seek, otherwise they are kept to be read
+    // before anything else
+    fn unread(&self, fd: &FileId, data: Vec<u8>) -> Result<(), AgentError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let channel = match self.proc_stdout_ids.read()?.get(fd) {
+            Some(pid) => Some((*pid, ProcessChannel::Stdout)),
+            None => self
+                .proc_stderr_ids
+                .read()?
+                .get(fd)
+                .map(|pid| (*pid, ProcessChannel::Stderr)),
+        };
+        match channel {
+            Some((proc_id, channel)) => {
+                let proc = self.get_process(&proc_id)?;
+                proc.output_channel(channel)?.write()?.unread(data);
+            }
+            None => {
+                self.unread.write()?.insert(*fd, data);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_text<R>(
+        &self,
+        fd: &FileId,
+        op: impl FnOnce(&mut TextReader<File>) -> Result<R, AgentError>,
+    ) -> Result<R, AgentError> {
+        let file_type = self.file_type(fd)?;
+        let pending = self.take_buffered_output(fd, usize::MAX)?;
+        let pending_cr = self.pending_cr.write()?.remove(fd);
+        let (result, unconsumed) = self.do_mut_operation(fd, |file| {
+            let from_file = pending.is_none();
+            let mut reader =
+                TextReader::new(file, file_type, pending.unwrap_or_default(), pending_cr);
+            let result = op(&mut reader);
+            if reader.pending_cr() {
+                if let Ok(mut pending_cr) = self.pending_cr.write() {
+                    pending_cr.insert(*fd);
+                }
+            }
+            let unconsumed = reader.into_unconsumed();
+            if from_file
+                && !unconsumed.is_empty()
+                && file
+                    .seek(SeekFrom::Current(-(unconsumed.len() as i64)))
+                    .is_ok()
+            {
+                return (result, Vec::new());
+            }
+            (result, unconsumed)
+        })?;
+        self.unread(fd, unconsumed)?;
+        result
+    }
+
+    pub fn list_detached_processes(&self) -> Result<Vec<DetachedProcessInfo>, AgentError> {
+        self.global.list_detached_processes()
+    }
+
+    pub fn watch_path(
+        &self,
+        path: &str,
+        recursive: bool,
+        events: &[WatchEventKind],
+    ) -> Result<WatchId, AgentError> {
+        let watcher = Watcher::new(path, recursive, events)?;
+        let mut next_watch_id = self.next_watch_id.write()?;
+        let watch_id = *next_watch_id;
+        *next_watch_id += 1;
+        self.watches.write()?.insert(watch_id, Arc::new(watcher));
+        Ok(watch_id)
+    }
+
+    pub fn watch_events(
+        &self,
+        watch_id: &WatchId,
+        max_events: usize,
+        timeout: Option<Duration>,
+        cancelled: &AtomicBool,
+    ) -> Result<Vec<WatchEvent>, AgentError> {
+        // The lock on the watch table isn't held while waiting for events
+        let watcher = self
+            .watches
+            .read()?
+            .get(watch_id)
+            .cloned()
+            .ok_or(InvalidWatchId)?;
+        watcher.events(max_events, timeout, cancelled)
+    }
+
+    pub fn unwatch(&self, watch_id: &WatchId) -> Result<(), AgentError> {
+        // Waiters still hold the watcher, so it has to be stopped to wake them up
+        self.watches
+            .write()?
+            .remove(watch_id)
+            .ok_or(InvalidWatchId)?
+            .stop();
+        Ok(())
+    }
+
+    pub fn temp_root(&self, env_id: EnvironmentId) -> &Path {
+        self.global.config.temp_root(env_id)
+    }
+
+    /// The roots paths are confined to. The connection's own temporary directories are allowed
+    /// along with the configured roots, as long as there are any.
+    pub fn allowed_roots(&self) -> Result<Vec<PathBuf>, AgentError> {
+        let roots = &self.global.config.allowed_roots;
+        if roots.is_empty() {
+            return Ok(Vec::new());
+        }
+        let session_temp_dirs = self.session_temp_dirs.read()?;
+        Ok(roots
+            .iter()
+            .cloned()
+            .chain(
+                session_temp_dirs
+                    .values()
+                    .filter_map(|dir| dir.canonicalize().ok()),
+            )
+            .collect())
+    }
+
+    /// Checks that a path is inside the allowed roots, and returns it resolved. Paths are returned
+    /// unchanged when the agent isn't restricted to any roots. The path is used after it was
+    /// checked, so a symlink swapped into it in between can still lead outside of the roots. Only
+    /// opens through open_jailed are safe from that.
+    pub fn jail_path(&self, path: &str, follow_symlinks: bool) -> Result<String, AgentError> {
+        jail_path(&self.allowed_roots()?, path, follow_symlinks)
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.global.config.read_only
+    }
+
+    /// Refuses writes to files opened with file_open in read-only mode. Process channels can still
+    /// be written to.
+    pub fn check_file_writable(&self, fd: &FileId) -> Result<(), AgentError> {
+        match self.read_only() && self.file_modes.read()?.contains_key(fd) {
+            true => Err(ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // Confines the paths in a process config. With allowed roots, the working directory has to be
+    // given, since the agent's own isn't necessarily inside them. In read-only mode the program has
+    // to be one of the allowed commands, and it's pinned so that the program that was checked is
+    // the one that runs. What the process does once it runs isn't restricted.
+    fn check_popen_config(&self, config: &mut RemotePOpenConfig) -> Result<(), AgentError> {
+        if self.read_only() {
+            if config.record.is_some() {
+                return Err(ReadOnly);
+            }
+            let program = find_program(config).ok_or(ReadOnly)?;
+            let allowed = self
+                .global
+                .config
+                .allowed_commands
+                .iter()
+                .any(|command| resolve_path(command, false).is_ok_and(|c| c == program));
+            if !allowed {
+                return Err(ReadOnly);
+            }
+            config.executable = Some(program.to_string_lossy().into_owned());
+        }
+        match &config.cwd {
+            Some(cwd) => config.cwd = Some(self.jail_path(cwd, true)?),
+            None if !self.global.config.allowed_roots.is_empty() => return Err(PathNotAllowed),
+            None => {}
+        }
+        if let Some(record) = &config.record {
+            config.record = Some(self.jail_path(record, true)?);
+        }
+        Ok(())
+    }
+
+    // The connection's private directory is only created once something is put in it
+    fn session_temp_dir(&self, env_id: EnvironmentId) -> Result<PathBuf, AgentError> {
+        let mut session_temp_dirs = self.session_temp_dirs.write()?;
+        if let Some(dir) = session_temp_dirs.get(&env_id) {
+            return Ok(dir.clone());
+        }
+        let root = self.temp_root(env_id);
+        std::fs::create_dir_all(root)?;
+        let dir = make_temp(root, "bh_session_", "", true)?;
+        session_temp_dirs.insert(env_id, dir.clone());
+        Ok(dir)
+    }
+
+    pub fn make_temp(
+        &self,
+        env_id: EnvironmentId,
+        prefix: &str,
+        suffix: &str,
+        directory: bool,
+    ) -> Result<String, AgentError> {
+        if self.read_only() {
+            return Err(ReadOnly);
+        }
+        let dir = self.session_temp_dir(env_id)?;
+        let path = make_temp(&dir, prefix, suffix, directory).map_err(|e| match e.kind() {
+            ErrorKind::InvalidInput => InvalidTempName,
+            _ => AgentError::from(e),
+        })?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Seeks a file. Text read before the seek no longer affects the next read.
+    pub fn seek_file(&self, fd: &FileId, from: SeekFrom) -> Result<u64, AgentError> {
+        self.pending_cr.write()?.remove(fd);
+        self.do_mut_operation(fd, |file| file.seek(from))?
+            .map_err(AgentError::from)
     }
 
     pub fn close_file(&self, fd: &FileId) -> Result<(), AgentError> {
-        Ok(drop(
-            self.files
-                .write()?
-                .remove(&fd)
-                .ok_or(InvalidFileDescriptor)?,
-        ))
+        self.unread.write()?.remove(fd);
+        self.pending_cr.write()?.remove(fd);
+        self.files
+            .write()?
+            .remove(fd)
+            .ok_or(InvalidFileDescriptor)?;
+        Ok(())
     }
 
     pub fn is_file_closed(&self, fd: &FileId) -> Result<bool, AgentError> {
-        Ok(self.files.read()?.contains_key(&fd))
+        match self.do_mut_operation(fd, |_| ()) {
+            Ok(()) => Ok(false),
+            Err(InvalidFileDescriptor) => Ok(true),
+            Err(e) => Err(e),
+        }
     }
 
     pub fn do_mut_operation<R: Sized>(
         &self,
         fd: &FileId,
-        op: impl Fn(&mut File) -> R,
+        op: impl FnOnce(&mut File) -> R,
     ) -> Result<R, AgentError> {
         // Get file logic
         if let Some(file_lock) = self.files.read()?.get(fd) {
@@ -213,25 +816,253 @@ impl BhAgentState {
         }
 
         // If these unwraps fail, the state is bad
-        if let Some(pid) = self.proc_stdin_ids.read()?.get(&fd) {
-            let procs_binding = self.processes.read()?;
-            let mut proc_binding = procs_binding.get(pid).unwrap().write()?;
-            let file = proc_binding.stdin.as_mut().unwrap();
-            return Ok(op(file));
+        if let Some(pid) = self.proc_stdin_ids.read()?.get(fd) {
+            let proc = self.get_process(pid)?;
+            let mut file = proc.stdin.as_ref().unwrap().write()?;
+            return Ok(op(&mut file));
         }
-        if let Some(pid) = self.proc_stdout_ids.read()?.get(&fd) {
-            let procs_binding = self.processes.read()?;
-            let mut proc_binding = procs_binding.get(pid).unwrap().write()?;
-            let file = proc_binding.stdout.as_mut().unwrap();
-            return Ok(op(file));
+        if let Some(pid) = self.proc_stdout_ids.read()?.get(fd) {
+            let proc = self.get_process(pid)?;
+            let mut output = proc.stdout.as_ref().unwrap().write()?;
+            return Ok(op(&mut output.file));
         }
-        if let Some(pid) = self.proc_stderr_ids.read()?.get(&fd) {
-            let procs_binding = self.processes.read()?;
-            let mut proc_binding = procs_binding.get(pid).unwrap().write()?;
-            let file = proc_binding.stderr.as_mut().unwrap();
-            return Ok(op(file));
+        if let Some(pid) = self.proc_stderr_ids.read()?.get(fd) {
+            let proc = self.get_process(pid)?;
+            let mut output = proc.stderr.as_ref().unwrap().write()?;
+            return Ok(op(&mut output.file));
         }
 
         Err(InvalidFileDescriptor)
     }
 }
+
+impl Drop for BhAgentState {
+    // Processes belonging to the connection are killed along with all of their descendants when
+    // the client disconnects. Detached processes are left running. The connection's temporary
+    // files are removed, unless a detached process the connection started or attached to is still
+    // running. Then they're kept until that process is forgotten.
+    fn drop(&mut self) {
+        let mut running_detached = Vec::new();
+        if let Ok(processes) = self.processes.read() {
+            for (proc_id, proc) in processes.iter() {
+                if !self.global.is_detached(proc_id).unwrap_or(true) {
+                    let _ = self.kill_process_tree(proc_id, proc);
+                    let _ = self.global.process_tracker.untrack(proc_id);
+                } else if proc.popen.write().is_ok_and(|mut p| p.poll().is_none()) {
+                    running_detached.push(*proc_id);
+                }
+            }
+        }
+        if let Ok(session_temp_dirs) = self.session_temp_dirs.read() {
+            let dirs: Vec<PathBuf> = session_temp_dirs.values().cloned().collect();
+            if running_detached.is_empty() {
+                for dir in &dirs {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+            }
+            for proc_id in running_detached {
+                let _ = self.global.keep_temp_dirs(&proc_id, &dirs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BhAgentConfig;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_forget_process() {
+        let global = Arc::new(BhAgentGlobalState::default());
+        let state = BhAgentState::new("127.0.0.1:1".parse().unwrap(), global.clone());
+        let temp = PathBuf::from(state.make_temp(0, "forget", "", false).unwrap());
+        let proc_id = state
+            .run_command(RemotePOpenConfig {
+                argv: vec!["sleep".to_string(), "60".to_string()],
+                stdout: Redirection::Save,
+                detached: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let proc = state.get_process(&proc_id).unwrap();
+        assert!(global.list_detached_processes().unwrap()[0].running);
+
+        // The session's temporary files outlive the connection while the process is running
+        drop(state);
+        assert!(temp.exists());
+        let state = BhAgentState::new("127.0.0.1:1".parse().unwrap(), global.clone());
+        state.attach_process(&proc_id).unwrap();
+        let stdout = state
+            .get_process_channel(&proc_id, ProcessChannel::Stdout)
+            .unwrap();
+        state.forget_process(&proc_id).unwrap();
+        assert!(!temp.parent().unwrap().exists());
+        assert!(global.list_detached_processes().unwrap().is_empty());
+        assert!(proc.popen.write().unwrap().poll().is_some());
+        assert!(matches!(state.get_process(&proc_id), Err(InvalidProcessId)));
+        assert!(matches!(
+            state.file_type(&stdout),
+            Err(InvalidFileDescriptor)
+        ));
+        assert!(matches!(
+            state.forget_process(&proc_id),
+            Err(InvalidProcessId)
+        ));
+    }
+
+    #[test]
+    fn test_process_cwd_jailed() {
+        let dir = TestDir::new("process_cwd");
+        let global = Arc::new(BhAgentGlobalState::new(BhAgentConfig {
+            allowed_roots: vec![dir.path().to_path_buf()],
+            ..Default::default()
+        }));
+        let state = BhAgentState::new("127.0.0.1:1".parse().unwrap(), global);
+        let config = |cwd: Option<&str>| RemotePOpenConfig {
+            argv: vec!["true".to_string()],
+            cwd: cwd.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            state.run_command(config(None)),
+            Err(PathNotAllowed)
+        ));
+        assert!(matches!(
+            state.run_command(config(Some("/"))),
+            Err(PathNotAllowed)
+        ));
+        let proc_id = state
+            .run_command(config(Some(dir.path().to_str().unwrap())))
+            .unwrap();
+        state
+            .get_process(&proc_id)
+            .unwrap()
+            .popen
+            .write()
+            .unwrap()
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_and_collect() {
+        let state = BhAgentState::new(
+            "127.0.0.1:1".parse().unwrap(),
+            Arc::new(BhAgentGlobalState::default()),
+        );
+        let sh = |script: &str| RemotePOpenConfig {
+            argv: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            ..Default::default()
+        };
+        let live = AtomicBool::new(false);
+
+        let output = state
+            .run_and_collect(sh("cat; echo err >&2; exit 3"), b"in".to_vec(), None, &live)
+            .unwrap();
+        assert_eq!(output.stdout, b"in");
+        assert_eq!(output.stderr, b"err\n");
+        assert!(matches!(output.exit_status, ExitStatus::Exited(3)));
+        assert!(!output.timed_out);
+
+        // A process that runs past the timeout is killed, keeping what it wrote so far
+        let start = Instant::now();
+        let output = state
+            .run_and_collect(
+                sh("echo started; exec sleep 60"),
+                Vec::new(),
+                Some(Duration::from_millis(300)),
+                &live,
+            )
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(output.stdout, b"started\n");
+        assert!(output.timed_out);
+        assert!(matches!(output.exit_status, ExitStatus::Signaled(9)));
+
+        // So is one whose request was cancelled
+        let cancelled = AtomicBool::new(true);
+        let output = state
+            .run_and_collect(sh("exec sleep 60"), Vec::new(), None, &cancelled)
+            .unwrap();
+        assert!(output.timed_out);
+    }
+
+    #[test]
+    fn test_open_path_modes() {
+        use bh_agent_common::AgentError::{AlreadyExists, NotFound};
+        use std::io::Read;
+
+        let dir = TestDir::new("open_modes");
+        let state = BhAgentState::new(
+            "127.0.0.1:1".parse().unwrap(),
+            Arc::new(BhAgentGlobalState::default()),
+        );
+        let open = |name: &str, mode: FileOpenMode| {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            state.open_path(path, mode, FileOpenType::Binary)
+        };
+        let read = |fd: &FileId| {
+            state
+                .do_mut_operation(fd, |file| {
+                    let mut buf = Vec::new();
+                    file.rewind()?;
+                    file.read_to_end(&mut buf).map(|_| buf)
+                })
+                .unwrap()
+        };
+        let write = |fd: &FileId, data: &[u8]| {
+            state
+                .do_mut_operation(fd, |file| {
+                    file.rewind()?;
+                    file.write_all(data)
+                })
+                .unwrap()
+        };
+        let contents = |name: &str| std::fs::read(dir.join(name)).unwrap();
+
+        // Each mode's flags: readable, writable, creates, truncates, exclusive and appends
+        let cases = [
+            (FileOpenMode::Read, "r"),
+            (FileOpenMode::ReadUpdate, "rw"),
+            (FileOpenMode::Write, "wct"),
+            (FileOpenMode::WriteUpdate, "rwct"),
+            (FileOpenMode::ExclusiveWrite, "wcx"),
+            (FileOpenMode::ExclusiveWriteUpdate, "rwcx"),
+            (FileOpenMode::Append, "wca"),
+            (FileOpenMode::AppendUpdate, "rwca"),
+        ];
+        for (i, (mode, flags)) in cases.into_iter().enumerate() {
+            let [readable, writable, creates, truncates, exclusive, appends] =
+                ['r', 'w', 'c', 't', 'x', 'a'].map(|flag| flags.contains(flag));
+            let existing = format!("existing{}", i);
+            std::fs::write(dir.join(&existing), b"old").unwrap();
+            match open(&existing, mode) {
+                Err(AlreadyExists) if exclusive => {}
+                Ok(fd) if !exclusive => {
+                    let expected: &[u8] = if truncates { b"" } else { b"old" };
+                    assert_eq!(contents(&existing), expected, "{:?}", mode);
+                    assert_eq!(read(&fd).is_ok(), readable, "{:?}", mode);
+                    assert_eq!(write(&fd, b"new").is_ok(), writable, "{:?}", mode);
+                    // Appending ignores the rewind before the write
+                    let expected: &[u8] = match (writable, appends) {
+                        (false, _) => b"old",
+                        (true, true) => b"oldnew",
+                        (true, false) => b"new",
+                    };
+                    assert_eq!(contents(&existing), expected, "{:?}", mode);
+                }
+                result => panic!("{:?} on an existing file gave {:?}", mode, result),
+            }
+
+            let missing = format!("missing{}", i);
+            match open(&missing, mode) {
+                Ok(_) if creates => assert_eq!(contents(&missing), b"", "{:?}", mode),
+                Err(NotFound) if !creates => assert!(!dir.join(&missing).exists()),
+                result => panic!("{:?} on a missing file gave {:?}", mode, result),
+            }
+        }
+    }
+}
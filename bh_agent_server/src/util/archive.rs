@@ -0,0 +1,324 @@
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use bh_agent_common::AgentError::{InvalidArchive, IoError, UnsafeArchivePath};
+use bh_agent_common::{AgentError, ArchiveFormat};
+
+fn detect_format(file: &mut File) -> Result<ArchiveFormat, AgentError> {
+    let mut magic = [0u8; 4];
+    let n = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(match &magic[..n] {
+        [0x1f, 0x8b, ..] => ArchiveFormat::TarGz,
+        [0x28, 0xb5, 0x2f, 0xfd] => ArchiveFormat::TarZst,
+        [b'P', b'K', 0x03, 0x04] | [b'P', b'K', 0x05, 0x06] => ArchiveFormat::Zip,
+        _ => ArchiveFormat::Tar,
+    })
+}
+
+/// Resolves where an entry should be written. Entries can't leave the destination, either with
+/// `..` or through a symlink extracted by an earlier entry. The destination must be canonical.
+fn entry_destination(dest: &Path, name: &Path) -> Result<PathBuf, AgentError> {
+    let mut relative = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return Err(UnsafeArchivePath),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Ok(dest.to_path_buf());
+    }
+    let path = dest.join(relative);
+    // The closest ancestor that already exists has to resolve to somewhere in the destination
+    let mut ancestor = path.parent();
+    while let Some(dir) = ancestor {
+        if dir.exists() {
+            if !dir.canonicalize()?.starts_with(dest) {
+                return Err(UnsafeArchivePath);
+            }
+            break;
+        }
+        ancestor = dir.parent();
+    }
+    Ok(path)
+}
+
+fn extract_tar(reader: impl Read, dest: &Path) -> Result<(), AgentError> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    for entry in archive.entries().map_err(|_| InvalidArchive)? {
+        let mut entry = entry.map_err(|_| InvalidArchive)?;
+        entry_destination(dest, &entry.path().map_err(|_| InvalidArchive)?)?;
+        // unpack_in does its own checks as well, and also validates hard link targets
+        if !entry.unpack_in(dest)? {
+            return Err(UnsafeArchivePath);
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(file: File, dest: &Path) -> Result<(), AgentError> {
+    let mut archive = ZipArchive::new(file).map_err(|_| InvalidArchive)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(|_| InvalidArchive)?;
+        let name = entry.enclosed_name().ok_or(UnsafeArchivePath)?.to_owned();
+        let path = entry_destination(dest, &name)?;
+        let mode = entry.unix_mode();
+        // Modes are set through the opened file, so they never apply through a symlink
+        let file = if entry.is_dir() {
+            // A symlink extracted by an earlier entry can't stand in for the directory
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+                return Err(UnsafeArchivePath);
+            }
+            std::fs::create_dir_all(&path)?;
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+                .open(&path)?
+        } else {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Replace whatever is there instead of writing through an existing symlink
+            if std::fs::symlink_metadata(&path).is_ok() {
+                std::fs::remove_file(&path)?;
+            }
+            if mode.is_some_and(|m| m & libc::S_IFMT == libc::S_IFLNK) {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                symlink(target, &path)?;
+                continue;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            std::io::copy(&mut entry, &mut file)?;
+            file
+        };
+        if let Some(mode) = mode {
+            file.set_permissions(Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extracts an archive into a directory, creating it if needed.
+pub fn extract_archive(
+    archive_path: &str,
+    dest_dir: &str,
+    format: Option<ArchiveFormat>,
+) -> Result<(), AgentError> {
+    let mut file = File::open(archive_path)?;
+    let format = match format {
+        Some(format) => format,
+        None => detect_format(&mut file)?,
+    };
+    std::fs::create_dir_all(dest_dir)?;
+    let dest = Path::new(dest_dir).canonicalize()?;
+    match format {
+        ArchiveFormat::Tar => extract_tar(file, &dest),
+        ArchiveFormat::TarGz => extract_tar(GzDecoder::new(file), &dest),
+        ArchiveFormat::TarZst => extract_tar(zstd::Decoder::new(file)?, &dest),
+        ArchiveFormat::Zip => extract_zip(file, &dest),
+    }
+}
+
+fn pack_tar<W: Write>(writer: W, src: &Path) -> Result<W, AgentError> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", src)?;
+    Ok(builder.into_inner()?)
+}
+
+fn pack_zip_dir(zip: &mut ZipWriter<File>, src: &Path, dir: &Path) -> Result<(), AgentError> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = path
+            .strip_prefix(src)
+            .map_err(|_| InvalidArchive)?
+            .to_string_lossy()
+            .into_owned();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        let options = FileOptions::default().unix_permissions(metadata.permissions().mode());
+        if metadata.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            zip.add_symlink(name, target.to_string_lossy(), options)
+                .map_err(|_| IoError)?;
+        } else if metadata.is_dir() {
+            zip.add_directory(name, options).map_err(|_| IoError)?;
+            pack_zip_dir(zip, src, &path)?;
+        } else {
+            zip.start_file(name, options).map_err(|_| IoError)?;
+            std::io::copy(&mut File::open(&path)?, zip)?;
+        }
+    }
+    Ok(())
+}
+
+/// Packs the contents of a directory into an archive. Symlinks are stored as links.
+pub fn pack_archive(
+    src_dir: &str,
+    archive_path: &str,
+    format: ArchiveFormat,
+) -> Result<(), AgentError> {
+    let src = Path::new(src_dir);
+    if !std::fs::metadata(src)?.is_dir() {
+        return Err(AgentError::NotADirectory);
+    }
+    let file = File::create(archive_path)?;
+    match format {
+        ArchiveFormat::Tar => {
+            pack_tar(file, src)?;
+        }
+        ArchiveFormat::TarGz => {
+            pack_tar(GzEncoder::new(file, Compression::default()), src)?.finish()?;
+        }
+        ArchiveFormat::TarZst => {
+            pack_tar(zstd::Encoder::new(file, 0)?, src)?.finish()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new(file);
+            pack_zip_dir(&mut zip, src, src)?;
+            zip.finish().map_err(|_| IoError)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_entry_destination() {
+        let dir = TestDir::new("entry");
+        std::fs::create_dir_all(dir.join("inside")).unwrap();
+        let dest = dir.path();
+        symlink("/", dest.join("escape")).unwrap();
+
+        assert_eq!(
+            entry_destination(dest, Path::new("./a/b")).unwrap(),
+            dest.join("a/b")
+        );
+        assert_eq!(
+            entry_destination(dest, Path::new("/etc/passwd")).unwrap(),
+            dest.join("etc/passwd")
+        );
+        assert!(entry_destination(dest, Path::new("inside/x")).is_ok());
+        assert!(matches!(
+            entry_destination(dest, Path::new("a/../../x")),
+            Err(UnsafeArchivePath)
+        ));
+        assert!(matches!(
+            entry_destination(dest, Path::new("escape/tmp/x")),
+            Err(UnsafeArchivePath)
+        ));
+    }
+
+    #[test]
+    fn test_extract_symlink_escape() {
+        let dir = TestDir::new("escape");
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        let archive = dir.join("evil.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "escape", dir.join("outside"))
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "escape/pwned", &b"pwned"[..])
+            .unwrap();
+        builder.finish().unwrap();
+
+        let dest = dir.join("dest");
+        assert!(matches!(
+            extract_archive(archive.to_str().unwrap(), dest.to_str().unwrap(), None),
+            Err(UnsafeArchivePath)
+        ));
+        assert!(!dir.join("outside/pwned").exists());
+
+        // A directory entry over a symlink mustn't change the link's target
+        let archive = dir.join("evil.zip");
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        zip.add_symlink(
+            "link",
+            dir.join("outside").to_str().unwrap(),
+            FileOptions::default(),
+        )
+        .unwrap();
+        zip.add_directory("link/", FileOptions::default().unix_permissions(0o777))
+            .unwrap();
+        zip.finish().unwrap();
+        let mode = || {
+            let metadata = std::fs::metadata(dir.join("outside")).unwrap();
+            metadata.permissions().mode() & 0o7777
+        };
+        let before = mode();
+        assert!(matches!(
+            extract_archive(archive.to_str().unwrap(), dest.to_str().unwrap(), None),
+            Err(UnsafeArchivePath)
+        ));
+        assert_eq!(mode(), before);
+    }
+
+    #[test]
+    fn test_pack_and_extract() {
+        let dir = TestDir::new("archive");
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("lib")).unwrap();
+        std::fs::write(src.join("run"), b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(src.join("run"), Permissions::from_mode(0o750)).unwrap();
+        std::fs::write(src.join("lib/libfoo.so.1"), b"library").unwrap();
+        symlink("libfoo.so.1", src.join("lib/libfoo.so")).unwrap();
+
+        for (format, name) in [
+            (ArchiveFormat::Tar, "a.tar"),
+            (ArchiveFormat::TarGz, "a.tar.gz"),
+            (ArchiveFormat::TarZst, "a.tar.zst"),
+            (ArchiveFormat::Zip, "a.zip"),
+        ] {
+            let archive = dir.join(name);
+            let dest = dir.join(format!("{}.out", name));
+            pack_archive(src.to_str().unwrap(), archive.to_str().unwrap(), format).unwrap();
+            assert_eq!(
+                detect_format(&mut File::open(&archive).unwrap()).unwrap(),
+                format
+            );
+            extract_archive(archive.to_str().unwrap(), dest.to_str().unwrap(), None).expect(name);
+
+            let mode = std::fs::metadata(dest.join("run"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o750, "{}", name);
+            assert_eq!(
+                std::fs::read(dest.join("lib/libfoo.so")).unwrap(),
+                b"library"
+            );
+            assert_eq!(
+                std::fs::read_link(dest.join("lib/libfoo.so")).unwrap(),
+                Path::new("libfoo.so.1")
+            );
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+
+use glob::Pattern;
+
+use bh_agent_common::AgentError::{AlreadyExists, InvalidPattern};
+use bh_agent_common::{AgentError, DirEntry};
+
+use crate::util::file_stat;
+
+fn list_dir_into(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&Pattern>,
+    with_stat: bool,
+    entries: &mut Vec<DirEntry>,
+) -> Result<(), AgentError> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+        if pattern.is_none_or(|p| p.matches(&entry.file_name().to_string_lossy())) {
+            let stat = file_stat(&metadata);
+            entries.push(DirEntry {
+                path: path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .into_owned(),
+                file_type: stat.file_type,
+                stat: with_stat.then_some(stat),
+            });
+        }
+        // Symlinks to directories are listed but not descended into, so links can't cause loops.
+        // Subdirectories that can't be read are skipped rather than failing the whole listing.
+        if recursive && metadata.is_dir() {
+            let _ = list_dir_into(root, &path, recursive, pattern, with_stat, entries);
+        }
+    }
+    Ok(())
+}
+
+/// Lists a directory, sorted by path. The pattern is a glob matched against each entry's name.
+pub fn list_dir(
+    path: &str,
+    recursive: bool,
+    pattern: Option<&str>,
+    with_stat: bool,
+) -> Result<Vec<DirEntry>, AgentError> {
+    let pattern = pattern
+        .map(Pattern::new)
+        .transpose()
+        .map_err(|_| InvalidPattern)?;
+    let root = Path::new(path);
+    let mut entries = Vec::new();
+    list_dir_into(
+        root,
+        root,
+        recursive,
+        pattern.as_ref(),
+        with_stat,
+        &mut entries,
+    )?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Creates a directory along with any missing parents, like os.makedirs.
+pub fn make_dirs(path: &str, mode: Option<u32>, exist_ok: bool) -> Result<(), AgentError> {
+    if !exist_ok && std::fs::symlink_metadata(path).is_ok() {
+        return Err(AlreadyExists);
+    }
+    DirBuilder::new()
+        .recursive(true)
+        .mode(mode.unwrap_or(0o777))
+        .create(path)?;
+    Ok(())
+}
+
+/// Removes a file, or a directory. Non-empty directories are only removed when recursive is set.
+/// Symlinks are removed themselves, never their targets.
+pub fn remove(path: &str, recursive: bool) -> Result<(), AgentError> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    match (metadata.is_dir(), recursive) {
+        (true, true) => std::fs::remove_dir_all(path)?,
+        (true, false) => std::fs::remove_dir(path)?,
+        (false, _) => std::fs::remove_file(path)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use bh_agent_common::FileType;
+
+    #[test]
+    fn test_dir_operations() {
+        let dir = TestDir::new("dir");
+        let root = dir.path().to_str().unwrap();
+        make_dirs(&format!("{}/a/b", root), None, false).unwrap();
+        assert!(matches!(
+            make_dirs(&format!("{}/a", root), None, false),
+            Err(AlreadyExists)
+        ));
+        make_dirs(&format!("{}/a", root), None, true).unwrap();
+        std::fs::write(dir.join("a/one.txt"), b"1").unwrap();
+        std::fs::write(dir.join("a/b/two.txt"), b"22").unwrap();
+        std::fs::write(dir.join("three.bin"), b"333").unwrap();
+
+        let paths = |entries: Vec<DirEntry>| -> Vec<String> {
+            entries.into_iter().map(|e| e.path).collect()
+        };
+        assert_eq!(
+            paths(list_dir(root, false, None, false).unwrap()),
+            vec!["a", "three.bin"]
+        );
+        assert_eq!(
+            paths(list_dir(root, true, Some("*.txt"), false).unwrap()),
+            vec!["a/b/two.txt", "a/one.txt"]
+        );
+        let entries = list_dir(root, true, Some("b"), true).unwrap();
+        assert_eq!(entries[0].file_type, FileType::Directory);
+        assert!(entries[0].stat.is_some());
+        assert!(matches!(
+            list_dir(root, false, Some("[a"), false),
+            Err(InvalidPattern)
+        ));
+
+        assert!(remove(&format!("{}/a", root), false).is_err());
+        remove(&format!("{}/three.bin", root), false).unwrap();
+        remove(root, true).unwrap();
+        assert!(!dir.path().exists());
+    }
+}
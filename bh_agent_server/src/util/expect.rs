@@ -0,0 +1,150 @@
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use bh_agent_common::AgentError::{EndOfFile, IoError, Timeout};
+use bh_agent_common::{AgentError, ExpectMatch};
+
+use crate::process::OutputChannel;
+use crate::util::Matcher;
+
+const READ_CHUNK_SIZE: usize = 4096;
+// How often a wait without output checks whether its request was cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits until the file has data to read, or the timeout expires. Returns false on timeout.
+pub fn wait_readable(file: &impl AsRawFd, timeout: Option<Duration>) -> std::io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = match timeout {
+        Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+        None => -1,
+    };
+    loop {
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            -1 => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => return Ok(false),
+            _ => return Ok(true),
+        }
+    }
+}
+
+/// Reads from a process output channel into its buffer until the pattern matches. The match and
+/// everything before it is removed from the buffer, anything after it is left for the next read.
+/// The channel is only locked while reading from it, not while waiting for output, and waiting
+/// stops once the timeout expires or `cancelled` is set.
+pub fn expect(
+    channel: &RwLock<OutputChannel>,
+    matcher: &Matcher,
+    timeout: Option<Duration>,
+    cancelled: &AtomicBool,
+) -> Result<ExpectMatch, AgentError> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+    loop {
+        // The pipe stays open while the channel exists, so it can be polled after unlocking
+        let fd = {
+            let mut output = channel.write()?;
+            let output = &mut *output;
+            if let Some(m) = matcher.find(&output.buffer) {
+                let mut consumed: Vec<u8> = output.buffer.drain(..m.end).collect();
+                let matched = consumed.split_off(m.start);
+                return Ok(ExpectMatch {
+                    before: consumed,
+                    matched,
+                    captures: m.captures,
+                });
+            }
+            if wait_readable(&output.file, Some(Duration::ZERO)).map_err(|_| IoError)? {
+                let bytes_read = output.file.read(&mut chunk).map_err(|_| IoError)?;
+                if bytes_read == 0 {
+                    return Err(EndOfFile);
+                }
+                output.buffer.extend_from_slice(&chunk[..bytes_read]);
+                continue;
+            }
+            output.file.as_raw_fd()
+        };
+
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        if cancelled.load(Ordering::Acquire) || remaining == Some(Duration::ZERO) {
+            return Err(Timeout);
+        }
+        let wait = remaining.map_or(CANCEL_CHECK_INTERVAL, |r| r.min(CANCEL_CHECK_INTERVAL));
+        wait_readable(&fd, Some(wait)).map_err(|_| IoError)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bh_agent_common::MatchPattern;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::fd::FromRawFd;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn pipe() -> (RwLock<OutputChannel>, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        (RwLock::new(OutputChannel::new(reader)), writer)
+    }
+
+    #[test]
+    fn test_expect_leaves_remainder_buffered() {
+        let (channel, mut writer) = pipe();
+        writer.write_all(b"Welcome\nChoice: 1\n").unwrap();
+        let matcher = Matcher::new(&MatchPattern::Regex(r"Choice: (\d)".to_string())).unwrap();
+        let m = expect(&channel, &matcher, None, &AtomicBool::new(false)).unwrap();
+        assert_eq!(m.before, b"Welcome\n");
+        assert_eq!(m.matched, b"Choice: 1");
+        assert_eq!(m.captures, vec![Some(b"1".to_vec())]);
+        assert_eq!(channel.read().unwrap().buffer, b"\n");
+    }
+
+    #[test]
+    fn test_expect_timeout_and_eof() {
+        let (channel, mut writer) = pipe();
+        writer.write_all(b"partial").unwrap();
+        let matcher = Matcher::new(&MatchPattern::Bytes(b"> ".to_vec())).unwrap();
+        let live = AtomicBool::new(false);
+        let result = expect(&channel, &matcher, Some(Duration::from_millis(10)), &live);
+        assert!(matches!(result, Err(Timeout)));
+        assert_eq!(channel.read().unwrap().buffer, b"partial");
+
+        drop(writer);
+        let result = expect(&channel, &matcher, None, &live);
+        assert!(matches!(result, Err(EndOfFile)));
+        assert_eq!(channel.read().unwrap().buffer, b"partial");
+    }
+
+    #[test]
+    fn test_expect_cancelled_unlocked() {
+        let (channel, _writer) = pipe();
+        let channel = Arc::new(channel);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let matcher = Matcher::new(&MatchPattern::Bytes(b"> ".to_vec())).unwrap();
+        let waiter = {
+            let (channel, cancelled) = (channel.clone(), cancelled.clone());
+            thread::spawn(move || expect(&channel, &matcher, None, &cancelled))
+        };
+
+        // The channel can be used while expect waits, and cancelling it ends the wait
+        thread::sleep(Duration::from_millis(50));
+        assert!(channel.try_write().is_ok());
+        cancelled.store(true, Ordering::Release);
+        assert!(matches!(waiter.join().unwrap(), Err(Timeout)));
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use bh_agent_common::AgentError::InvalidHash;
+use bh_agent_common::{AgentError, ByteRange, HashAlgorithm};
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha1(h) => format!("{:x}", h.finalize()),
+            Hasher::Md5(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Checks that a hash is a SHA-256 digest in lowercase hex, so it's safe to use in a file name.
+pub fn check_sha256(hash: &str) -> Result<(), AgentError> {
+    match hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        true => Ok(()),
+        false => Err(InvalidHash),
+    }
+}
+
+/// Hashes a range of a file, or all of it, and returns the digest as lowercase hex. The file is
+/// read with positioned reads, so its position is left alone.
+pub fn hash_file(
+    file: &File,
+    algorithm: HashAlgorithm,
+    range: Option<ByteRange>,
+) -> Result<String, AgentError> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut offset = range.map_or(0, |r| r.offset);
+    let mut remaining = range.and_then(|r| r.length).unwrap_or(u64::MAX);
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    while remaining > 0 {
+        let want = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        match file.read_at(&mut buf[..want], offset) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+                offset += n as u64;
+                remaining -= n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hasher.finalize_hex())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_hash_file() {
+        let dir = TestDir::new("hash");
+        let path = dir.join("file");
+        std::fs::write(&path, b"abc").unwrap();
+        let file = File::open(&path).unwrap();
+
+        let hash = |algorithm| hash_file(&file, algorithm, None).unwrap();
+        assert_eq!(
+            hash(HashAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hash(HashAlgorithm::Sha1),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(hash(HashAlgorithm::Md5), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hash(HashAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        let range = ByteRange {
+            offset: 1,
+            length: Some(1),
+        };
+        assert_eq!(
+            hash_file(&file, HashAlgorithm::Md5, Some(range)).unwrap(),
+            format!("{:x}", Md5::digest(b"b"))
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use bh_agent_common::AgentError;
+use bh_agent_common::AgentError::PathNotAllowed;
+
+// Linux stops following symlinks after this many, and so does resolve_path
+const MAX_SYMLINKS: usize = 40;
+
+// struct open_how from linux/openat2.h
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+// Resolves a directory that may not exist yet, like the parents of a path that make_dirs is about
+// to create. The nearest ancestor that exists is canonicalized, and the missing components are
+// appended to it. A dangling symlink in place of the first missing one is followed, since creating
+// the directory would go through it.
+fn resolve_dir(dir: &Path, links: usize) -> io::Result<PathBuf> {
+    let components: Vec<Component> = dir.components().collect();
+    for split in (0..=components.len()).rev() {
+        let existing: PathBuf = components[..split].iter().collect();
+        let mut resolved = match existing.as_os_str().is_empty() {
+            true => std::env::current_dir()?,
+            false => match existing.canonicalize() {
+                Ok(resolved) => resolved,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            },
+        };
+        let mut first = true;
+        for (i, component) in components.iter().enumerate().skip(split) {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    if first && std::fs::symlink_metadata(&resolved).is_ok_and(|m| m.is_symlink()) {
+                        if links >= MAX_SYMLINKS {
+                            return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                        }
+                        let target = std::fs::read_link(&resolved)?;
+                        resolved.pop();
+                        let rest: PathBuf = components[i + 1..].iter().collect();
+                        return resolve_dir(&resolved.join(target).join(rest), links + 1);
+                    }
+                    first = false;
+                }
+                // `..` can't be resolved inside a directory that doesn't exist, and the OS would
+                // fail to look it up too
+                _ => return Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+        return Ok(resolved);
+    }
+    Err(io::ErrorKind::NotFound.into())
+}
+
+fn resolve_path_inner(path: &Path, follow_last: bool, links: usize) -> io::Result<PathBuf> {
+    if follow_last {
+        match path.canonicalize() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.canonicalize();
+    };
+    let parent = match parent.as_os_str().is_empty() {
+        true => std::env::current_dir()?,
+        false => resolve_dir(parent, links)?,
+    };
+    let resolved = parent.join(name);
+    // A dangling symlink as the last component would be created through, so it's followed too
+    match std::fs::symlink_metadata(&resolved) {
+        Ok(metadata) if follow_last && metadata.is_symlink() => {
+            if links >= MAX_SYMLINKS {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            let target = std::fs::read_link(&resolved)?;
+            resolve_path_inner(&parent.join(target), true, links + 1)
+        }
+        _ => Ok(resolved),
+    }
+}
+
+/// Resolves a path to an absolute one without any symlinks or `.` and `..` components. A symlink
+/// as the last component is only followed when asked. The last component doesn't have to exist,
+/// so paths that are about to be created can be resolved too.
+pub fn resolve_path(path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+    resolve_path_inner(path, follow_last, 0)
+}
+
+// Returns the allowed root that contains an already resolved path
+fn containing_root<'a>(roots: &'a [PathBuf], resolved: &Path) -> Option<&'a PathBuf> {
+    roots.iter().find(|root| resolved.starts_with(root))
+}
+
+/// Resolves a path and checks that it is inside one of the allowed roots, which must be
+/// canonical. Without any roots every path is allowed and returned as is.
+pub fn jail_path(roots: &[PathBuf], path: &str, follow_last: bool) -> Result<String, AgentError> {
+    if roots.is_empty() {
+        return Ok(path.to_string());
+    }
+    let resolved = resolve_path(Path::new(path), follow_last)?;
+    if containing_root(roots, &resolved).is_none() {
+        return Err(PathNotAllowed);
+    }
+    resolved
+        .into_os_string()
+        .into_string()
+        .map_err(|_| PathNotAllowed)
+}
+
+fn c_path(path: &Path) -> Result<CString, AgentError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| AgentError::IoError)
+}
+
+/// Opens a path with the given open(2) flags. With allowed roots, the path is opened with openat2
+/// and RESOLVE_BENEATH from the root that contains it, so a symlink swapped in after the path was
+/// checked can't lead the open outside of the root. With O_NOFOLLOW, a symlink as the last
+/// component isn't followed while checking the path either.
+pub fn open_jailed(roots: &[PathBuf], path: &str, flags: libc::c_int) -> Result<File, AgentError> {
+    let flags = flags | libc::O_CLOEXEC;
+    let fd = if roots.is_empty() {
+        let path = c_path(Path::new(path))?;
+        unsafe { libc::open(path.as_ptr(), flags, 0o666 as libc::c_uint) }
+    } else {
+        let follow_last = flags & libc::O_NOFOLLOW == 0;
+        let resolved = PathBuf::from(jail_path(roots, path, follow_last)?);
+        let root = containing_root(roots, &resolved).ok_or(PathNotAllowed)?;
+        let relative = match resolved.strip_prefix(root) {
+            Ok(relative) if relative.as_os_str().is_empty() => Path::new("."),
+            Ok(relative) => relative,
+            Err(_) => return Err(PathNotAllowed),
+        };
+
+        let root = c_path(root)?;
+        let root_fd = unsafe { libc::open(root.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if root_fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let root_fd = unsafe { OwnedFd::from_raw_fd(root_fd) };
+        let relative = c_path(relative)?;
+        let how = OpenHow {
+            flags: flags as u64,
+            mode: match flags & (libc::O_CREAT | libc::O_TMPFILE) {
+                0 => 0,
+                _ => 0o666,
+            },
+            resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS,
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                root_fd.as_raw_fd(),
+                relative.as_ptr(),
+                &how as *const OpenHow,
+                std::mem::size_of::<OpenHow>(),
+            ) as libc::c_int
+        }
+    };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        // openat2 refuses to resolve outside of the root with EXDEV
+        return match err.raw_os_error() {
+            Some(libc::EXDEV) => Err(PathNotAllowed),
+            _ => Err(err.into()),
+        };
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use bh_agent_common::AgentError::NotFound;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_jail() {
+        let dir = TestDir::new("jail");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(dir.join("outside"), b"secret").unwrap();
+        std::fs::write(root.join("inside"), b"ok").unwrap();
+        symlink("../outside", root.join("escape")).unwrap();
+        symlink("../outside_new", root.join("dangling")).unwrap();
+        symlink("inside", root.join("link")).unwrap();
+        let roots = vec![root.clone()];
+        let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+
+        assert_eq!(
+            jail_path(&roots, &path("sub/../inside"), true).unwrap(),
+            path("inside")
+        );
+        assert_eq!(jail_path(&roots, &path("new"), true).unwrap(), path("new"));
+        assert_eq!(
+            jail_path(&roots, &path("link"), true).unwrap(),
+            path("inside")
+        );
+        assert_eq!(
+            jail_path(&roots, &path("escape"), false).unwrap(),
+            path("escape")
+        );
+        for name in ["escape", "dangling", "../outside", ".."] {
+            assert!(matches!(
+                jail_path(&roots, &path(name), true),
+                Err(PathNotAllowed)
+            ));
+        }
+        assert!(matches!(
+            jail_path(&roots, "/etc/passwd", true),
+            Err(PathNotAllowed)
+        ));
+        assert_eq!(jail_path(&[], "../x", true).unwrap(), "../x");
+
+        let file = open_jailed(&roots, &path("inside"), libc::O_RDONLY).unwrap();
+        assert_eq!(std::io::read_to_string(file).unwrap(), "ok");
+        assert!(matches!(
+            open_jailed(&roots, &path("dangling"), libc::O_WRONLY | libc::O_CREAT),
+            Err(PathNotAllowed)
+        ));
+        assert!(!dir.join("outside_new").exists());
+    }
+
+    #[test]
+    fn test_jail_nested_missing() {
+        let dir = TestDir::new("jail_nested");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        symlink("../../outside", root.join("sub/dangling")).unwrap();
+        symlink("sub", root.join("link")).unwrap();
+        let roots = vec![root.clone()];
+        let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+
+        assert_eq!(
+            jail_path(&roots, &path("a/b/c"), true).unwrap(),
+            path("a/b/c")
+        );
+        assert_eq!(
+            jail_path(&roots, &path("link/./a/b"), false).unwrap(),
+            path("sub/a/b")
+        );
+        // The missing directories can't be left with `..`, and a dangling symlink among them
+        // leads where creating them would
+        assert!(matches!(
+            jail_path(&roots, &path("a/../../b"), true),
+            Err(NotFound)
+        ));
+        assert!(matches!(
+            jail_path(&roots, &path("sub/dangling/a/b"), true),
+            Err(PathNotAllowed)
+        ));
+    }
+}
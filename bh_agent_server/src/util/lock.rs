@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bh_agent_common::AgentError::{FileLocked, Timeout};
+use bh_agent_common::{AgentError, LockKind};
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn flock(file: &File, operation: i32) -> std::io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Takes an advisory flock() lock on the file. A blocking flock() can't be interrupted, so waiting
+/// polls instead, until the timeout passes or `cancelled` is set. A lock taken after the request
+/// was cancelled is released again, since nobody would know to unlock it.
+pub fn lock_file(
+    file: &File,
+    kind: LockKind,
+    blocking: bool,
+    timeout: Option<Duration>,
+    cancelled: &AtomicBool,
+) -> Result<(), AgentError> {
+    let operation = match kind {
+        LockKind::Shared => libc::LOCK_SH,
+        LockKind::Exclusive => libc::LOCK_EX,
+    };
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if cancelled.load(Ordering::Acquire) {
+            return Err(Timeout);
+        }
+        match flock(file, operation | libc::LOCK_NB) {
+            Ok(()) if cancelled.load(Ordering::Acquire) => {
+                unlock_file(file)?;
+                return Err(Timeout);
+            }
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        if !blocking {
+            return Err(FileLocked);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Timeout);
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+pub fn unlock_file(file: &File) -> Result<(), AgentError> {
+    Ok(flock(file, libc::LOCK_UN)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_lock_file() {
+        let dir = TestDir::new("lock");
+        let path = dir.join("file");
+        let first = File::create(&path).unwrap();
+        let second = File::open(&path).unwrap();
+        let live = AtomicBool::new(false);
+
+        lock_file(&first, LockKind::Shared, false, None, &live).unwrap();
+        lock_file(&second, LockKind::Shared, false, None, &live).unwrap();
+        assert!(matches!(
+            lock_file(&second, LockKind::Exclusive, false, None, &live),
+            Err(FileLocked)
+        ));
+        assert!(matches!(
+            lock_file(
+                &second,
+                LockKind::Exclusive,
+                true,
+                Some(Duration::from_millis(50)),
+                &live
+            ),
+            Err(Timeout)
+        ));
+        unlock_file(&first).unwrap();
+        lock_file(
+            &second,
+            LockKind::Exclusive,
+            true,
+            Some(Duration::from_secs(1)),
+            &live,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_lock_file_cancelled() {
+        let dir = TestDir::new("lock_cancelled");
+        let path = dir.join("file");
+        let first = File::create(&path).unwrap();
+        let second = File::open(&path).unwrap();
+        let cancelled = AtomicBool::new(false);
+
+        lock_file(&first, LockKind::Exclusive, false, None, &cancelled).unwrap();
+        thread::scope(|scope| {
+            let waiter =
+                scope.spawn(|| lock_file(&second, LockKind::Exclusive, true, None, &cancelled));
+            thread::sleep(Duration::from_millis(50));
+            cancelled.store(true, Ordering::Release);
+            assert!(matches!(waiter.join().unwrap(), Err(Timeout)));
+        });
+
+        // The cancelled waiter must not be left holding the lock
+        unlock_file(&first).unwrap();
+        let live = AtomicBool::new(false);
+        lock_file(&first, LockKind::Exclusive, false, None, &live).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Result, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::FileExt;
+
+/// Creates an anonymous in-memory file. The file is close-on-exec, so it can be executed through
+/// /proc/self/fd like fexecve does, but isn't leaked into the process it runs. As with fexecve,
+/// this means scripts can't be executed this way, since the interpreter can't open the file.
+pub fn memfd_create(name: &str) -> Result<File> {
+    let name = CString::new(name)?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn memfd_from_bytes(name: &str, data: &[u8]) -> Result<File> {
+    let mut memfd = memfd_create(name)?;
+    memfd.write_all(data)?;
+    Ok(memfd)
+}
+
+/// Copies the entire contents of the file into a new memfd, without moving the file's cursor.
+pub fn memfd_from_file(name: &str, file: &File) -> Result<File> {
+    let mut memfd = memfd_create(name)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = file.read_at(&mut buffer, offset)?;
+        if n == 0 {
+            break;
+        }
+        memfd.write_all(&buffer[..n])?;
+        offset += n as u64;
+    }
+    Ok(memfd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn test_memfd_from_file_keeps_cursor() {
+        let mut source = memfd_from_bytes("source", b"\x7fELF contents").unwrap();
+        source.seek(SeekFrom::Start(4)).unwrap();
+
+        let mut copy = memfd_from_file("copy", &source).unwrap();
+        let mut contents = Vec::new();
+        copy.seek(SeekFrom::Start(0)).unwrap();
+        copy.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"\x7fELF contents");
+        assert_eq!(source.stream_position().unwrap(), 4);
+    }
+}
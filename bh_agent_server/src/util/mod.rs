@@ -1,5 +1,37 @@
-mod read_chars;
-mod read_lines;
+mod archive;
+mod dir;
+mod expect;
+mod hash;
+mod jail;
+mod lock;
+mod memfd;
+mod pattern;
+mod read_at;
+mod search;
+mod stat;
+mod temp;
+#[cfg(test)]
+mod test_dir;
+mod text;
+mod transfer;
+mod utime;
+mod xattr;
 
-pub use read_chars::*;
-pub use read_lines::read_lines;
+pub use archive::*;
+pub use dir::*;
+pub use expect::*;
+pub use hash::*;
+pub use jail::*;
+pub use lock::*;
+pub use memfd::*;
+pub use pattern::*;
+pub use read_at::*;
+pub use search::*;
+pub use stat::*;
+pub use temp::*;
+#[cfg(test)]
+pub use test_dir::*;
+pub use text::*;
+pub use transfer::*;
+pub use utime::*;
+pub use xattr::*;
@@ -0,0 +1,88 @@
+use regex::bytes::Regex;
+
+use bh_agent_common::AgentError::InvalidPattern;
+use bh_agent_common::{AgentError, MatchPattern};
+
+pub struct PatternMatch {
+    pub start: usize,
+    pub end: usize,
+    pub captures: Vec<Option<Vec<u8>>>,
+}
+
+/// A compiled MatchPattern
+pub enum Matcher {
+    Regex(Regex),
+    Bytes(Vec<u8>),
+}
+
+impl Matcher {
+    pub fn new(pattern: &MatchPattern) -> Result<Self, AgentError> {
+        match pattern {
+            MatchPattern::Regex(re) => Regex::new(re)
+                .map(Matcher::Regex)
+                .map_err(|_| InvalidPattern),
+            MatchPattern::Bytes(b) if b.is_empty() => Err(InvalidPattern),
+            MatchPattern::Bytes(b) => Ok(Matcher::Bytes(b.clone())),
+        }
+    }
+
+    /// Finds the first match in the haystack
+    pub fn find(&self, haystack: &[u8]) -> Option<PatternMatch> {
+        self.find_at(haystack, 0)
+    }
+
+    /// Finds the first match starting at or after the given offset
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<PatternMatch> {
+        match self {
+            Matcher::Regex(re) => re.captures_at(haystack, start).map(|caps| {
+                let whole = caps.get(0).unwrap();
+                PatternMatch {
+                    start: whole.start(),
+                    end: whole.end(),
+                    captures: caps
+                        .iter()
+                        .skip(1)
+                        .map(|c| c.map(|m| m.as_bytes().to_vec()))
+                        .collect(),
+                }
+            }),
+            Matcher::Bytes(needle) => haystack
+                .get(start..)?
+                .windows(needle.len())
+                .position(|w| w == needle.as_slice())
+                .map(|pos| PatternMatch {
+                    start: start + pos,
+                    end: start + pos + needle.len(),
+                    captures: Vec::new(),
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex_captures() {
+        let matcher = Matcher::new(&MatchPattern::Regex(r"(\w+)=(\d+)?".to_string())).unwrap();
+        let m = matcher.find(b"> key= value").unwrap();
+        assert_eq!((m.start, m.end), (2, 6));
+        assert_eq!(m.captures, vec![Some(b"key".to_vec()), None]);
+    }
+
+    #[test]
+    fn test_bytes_find_at() {
+        let matcher = Matcher::new(&MatchPattern::Bytes(b"\x00\xff".to_vec())).unwrap();
+        let haystack = b"\x00\xff..\x00\xff";
+        assert_eq!(matcher.find(haystack).unwrap().start, 0);
+        assert_eq!(matcher.find_at(haystack, 1).unwrap().start, 4);
+        assert!(matcher.find_at(haystack, 5).is_none());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(Matcher::new(&MatchPattern::Regex("(".to_string())).is_err());
+        assert!(Matcher::new(&MatchPattern::Bytes(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io::{ErrorKind, Result};
+use std::os::unix::fs::FileExt;
+
+/// Positional reads are refused past this size, since their buffer is allocated up front
+pub const MAX_READ_AT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads up to `length` bytes starting at `offset` without moving the file's position. Fewer bytes
+/// are only returned at the end of the file.
+pub fn read_full_at(file: &File, offset: u64, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    let mut filled = 0;
+    while filled < data.len() {
+        match file.read_at(&mut data[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_read_full_at() {
+        let dir = TestDir::new("read_at");
+        let path = dir.join("file");
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+
+        assert_eq!(read_full_at(&file, 3, 4).unwrap(), b"3456");
+        assert_eq!(read_full_at(&file, 8, 4).unwrap(), b"89");
+        assert_eq!(read_full_at(&file, 20, 4).unwrap(), b"");
+        assert_eq!(file.stream_position().unwrap(), 2);
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use bh_agent_common::{
+    AgentError, FileType, MatchPattern, SearchLimits, SearchMatch, SearchResult,
+};
+
+use crate::util::{list_dir, Matcher};
+
+// Files are read a window at a time, so a search never holds more than a window plus the overlap
+const WINDOW_SIZE: usize = 1024 * 1024;
+// Regex matches can be arbitrarily long, but a window only has to overlap the next by this much.
+// Longer matches are cut short at the end of the window.
+const MAX_REGEX_MATCH_LEN: usize = 64 * 1024;
+
+struct Search {
+    matcher: Matcher,
+    context: usize,
+    // How much of the end of a window is searched again with the next one, so that matches and
+    // their context aren't cut apart at window boundaries
+    overlap: usize,
+    max_matches: usize,
+    max_bytes: u64,
+    result: SearchResult,
+}
+
+impl Search {
+    // Searches one file, returning false once a limit has stopped the search
+    fn search_file(&mut self, path: &Path, name: &str) -> Result<bool, AgentError> {
+        let remaining = self.max_bytes - self.result.bytes_scanned;
+        // One byte past the budget is read to tell whether the file was cut short
+        let mut file = File::open(path)?.take(remaining.saturating_add(1));
+        let mut read = 0u64;
+        let mut cut_short = false;
+        let mut eof = false;
+
+        // The window starts at this offset in the file. Line numbers are counted up to line_offset.
+        let mut data = Vec::new();
+        let mut base = 0u64;
+        let mut line = 1;
+        let mut line_offset = 0;
+        let mut start = 0;
+        while !eof {
+            let filled = data.len();
+            data.resize(filled + WINDOW_SIZE, 0);
+            let mut end = filled;
+            while end < data.len() {
+                match file.read(&mut data[end..])? {
+                    0 => {
+                        eof = true;
+                        break;
+                    }
+                    n => end += n,
+                }
+            }
+            data.truncate(end);
+            read += (end - filled) as u64;
+            if read > remaining {
+                data.truncate(data.len() - (read - remaining) as usize);
+                read = remaining;
+                cut_short = true;
+                eof = true;
+            }
+
+            // Matches starting in the overlap are left for the next window, which sees what
+            // follows them, unless there is no next window
+            let safe_end = match eof {
+                true => data.len() + 1,
+                false => data.len().saturating_sub(self.overlap),
+            };
+            while start < safe_end {
+                let Some(m) = self.matcher.find_at(&data, start) else {
+                    start = safe_end;
+                    break;
+                };
+                if m.start >= safe_end {
+                    start = m.start;
+                    break;
+                }
+                if self.result.matches.len() >= self.max_matches {
+                    self.result.bytes_scanned += read;
+                    self.result.truncated = true;
+                    return Ok(false);
+                }
+                line += data[line_offset..m.start]
+                    .iter()
+                    .filter(|b| **b == b'\n')
+                    .count() as u64;
+                line_offset = m.start;
+                self.result.matches.push(SearchMatch {
+                    path: name.to_string(),
+                    offset: base + m.start as u64,
+                    line,
+                    matched: data[m.start..m.end].to_vec(),
+                    before: data[m.start.saturating_sub(self.context)..m.start].to_vec(),
+                    after: data[m.end..data.len().min(m.end + self.context)].to_vec(),
+                });
+                // Empty matches would otherwise be found again at the same place
+                start = m.end.max(m.start + 1);
+            }
+
+            // Keep what the next window needs: the context before where it starts searching, and a
+            // byte more so that anchors like \b see what precedes it
+            let keep = start.min(data.len()).saturating_sub(self.context + 1);
+            let line_keep = keep.max(line_offset);
+            line += data[line_offset..line_keep]
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count() as u64;
+            line_offset = line_keep - keep;
+            data.drain(..keep);
+            base += keep as u64;
+            start -= keep;
+        }
+
+        self.result.bytes_scanned += read;
+        if cut_short {
+            self.result.truncated = true;
+        }
+        Ok(!cut_short)
+    }
+}
+
+/// Searches a file, or every regular file under a directory in path order, for a pattern.
+pub fn search(
+    path: &str,
+    pattern: &MatchPattern,
+    context: u32,
+    limits: SearchLimits,
+) -> Result<SearchResult, AgentError> {
+    let mut search = Search {
+        matcher: Matcher::new(pattern)?,
+        context: context as usize,
+        overlap: context as usize
+            + match pattern {
+                MatchPattern::Regex(_) => MAX_REGEX_MATCH_LEN,
+                MatchPattern::Bytes(b) => b.len(),
+            },
+        max_matches: limits.max_matches.map_or(usize::MAX, |m| m as usize),
+        max_bytes: limits.max_bytes.unwrap_or(u64::MAX),
+        result: SearchResult {
+            matches: Vec::new(),
+            bytes_scanned: 0,
+            truncated: false,
+        },
+    };
+
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_dir() {
+        let root = Path::new(path);
+        for entry in list_dir(path, true, None, false)? {
+            if entry.file_type != FileType::File {
+                continue;
+            }
+            // Files that can't be read are skipped, like unreadable directories in list_dir
+            if let Ok(false) = search.search_file(&root.join(&entry.path), &entry.path) {
+                break;
+            }
+        }
+    } else if metadata.is_file() {
+        search.search_file(Path::new(path), path)?;
+    } else {
+        // Devices like /dev/zero never end
+        return Err(AgentError::NotARegularFile);
+    }
+    Ok(search.result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_search() {
+        let dir = TestDir::new("search");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.bin"), b"\x7fELF\x00key=1\nkey=22\n").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), b"no\nmatch\nkey=333").unwrap();
+        let dir_path = dir.path().to_str().unwrap();
+
+        let pattern = MatchPattern::Regex(r"key=\d+".to_string());
+        let result = search(dir_path, &pattern, 2, SearchLimits::default()).unwrap();
+        assert!(!result.truncated);
+        assert_eq!(result.bytes_scanned, 34);
+        let found: Vec<_> = result
+            .matches
+            .iter()
+            .map(|m| (m.path.as_str(), m.offset, m.line, m.matched.as_slice()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("a.bin", 5, 1, &b"key=1"[..]),
+                ("a.bin", 11, 2, &b"key=22"[..]),
+                ("sub/b.txt", 9, 3, &b"key=333"[..]),
+            ]
+        );
+        assert_eq!(result.matches[0].before, b"F\x00");
+        assert_eq!(result.matches[0].after, b"\nk");
+        assert_eq!(result.matches[2].after, b"");
+
+        let result = search(
+            dir_path,
+            &pattern,
+            0,
+            SearchLimits {
+                max_matches: Some(2),
+                max_bytes: None,
+            },
+        )
+        .unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.matches.len(), 2);
+
+        let result = search(
+            dir_path,
+            &pattern,
+            0,
+            SearchLimits {
+                max_matches: None,
+                max_bytes: Some(12),
+            },
+        )
+        .unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.bytes_scanned, 12);
+        assert_eq!(result.matches.len(), 1);
+
+        let magic = MatchPattern::Bytes(b"\x7fELF".to_vec());
+        let file = dir.join("a.bin");
+        let result = search(file.to_str().unwrap(), &magic, 0, SearchLimits::default()).unwrap();
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.matches[0].path, file.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_search_windows() {
+        let dir = TestDir::new("search_windows");
+        let file = dir.join("big");
+        // Matches straddle the first and second window boundaries, with a line before each
+        let mut data = vec![b'.'; 3 * WINDOW_SIZE];
+        for at in [WINDOW_SIZE - 3, 2 * WINDOW_SIZE - 64 * 1024 - 2] {
+            data[at - 10] = b'\n';
+            data[at..at + 6].copy_from_slice(b"needle");
+        }
+        std::fs::write(&file, &data).unwrap();
+        let file = file.to_str().unwrap();
+
+        for pattern in [
+            MatchPattern::Bytes(b"needle".to_vec()),
+            MatchPattern::Regex("ne+dle".to_string()),
+        ] {
+            let result = search(file, &pattern, 4, SearchLimits::default()).unwrap();
+            assert_eq!(result.bytes_scanned, data.len() as u64);
+            let found: Vec<_> = result.matches.iter().map(|m| (m.offset, m.line)).collect();
+            assert_eq!(
+                found,
+                vec![
+                    ((WINDOW_SIZE - 3) as u64, 2),
+                    ((2 * WINDOW_SIZE - 64 * 1024 - 2) as u64, 3)
+                ]
+            );
+            for m in &result.matches {
+                assert_eq!(m.before, b"....");
+                assert_eq!(m.after, b"....");
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_not_regular() {
+        let pattern = MatchPattern::Bytes(b"x".to_vec());
+        assert!(matches!(
+            search("/dev/zero", &pattern, 0, SearchLimits::default()),
+            Err(AgentError::NotARegularFile)
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs::Metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use bh_agent_common::{FileStat, FileType};
+
+fn file_type(metadata: &Metadata) -> FileType {
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        FileType::File
+    } else if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_fifo() {
+        FileType::Fifo
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::Unknown
+    }
+}
+
+fn timestamp_ns(secs: i64, nsecs: i64) -> i64 {
+    secs.saturating_mul(1_000_000_000).saturating_add(nsecs)
+}
+
+pub fn file_stat(metadata: &Metadata) -> FileStat {
+    FileStat {
+        file_type: file_type(metadata),
+        size: metadata.size(),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        dev: metadata.dev(),
+        inode: metadata.ino(),
+        nlink: metadata.nlink(),
+        atime_ns: timestamp_ns(metadata.atime(), metadata.atime_nsec()),
+        mtime_ns: timestamp_ns(metadata.mtime(), metadata.mtime_nsec()),
+        ctime_ns: timestamp_ns(metadata.ctime(), metadata.ctime_nsec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_file_stat() {
+        let dir = TestDir::new("stat");
+        let file = dir.join("file");
+        std::fs::write(&file, b"hello").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let stat = file_stat(&std::fs::metadata(&link).unwrap());
+        assert_eq!(stat.file_type, FileType::File);
+        assert_eq!(stat.size, 5);
+        assert_eq!(stat.mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(stat.nlink, 1);
+        assert!(stat.mtime_ns > 0);
+
+        let stat = file_stat(&std::fs::symlink_metadata(&link).unwrap());
+        assert_eq!(stat.file_type, FileType::Symlink);
+
+        let stat = file_stat(&std::fs::metadata(dir.path()).unwrap());
+        assert_eq!(stat.file_type, FileType::Directory);
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs::{DirBuilder, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+const MAX_ATTEMPTS: u32 = 128;
+
+fn random_name(prefix: &str, suffix: &str) -> io::Result<String> {
+    let mut random = [0u8; 8];
+    getrandom::getrandom(&mut random).map_err(io::Error::from)?;
+    Ok(format!(
+        "{}{:016x}{}",
+        prefix,
+        u64::from_ne_bytes(random),
+        suffix
+    ))
+}
+
+/// Creates a uniquely named file or directory in `dir`, readable only by the agent's user.
+pub fn make_temp(dir: &Path, prefix: &str, suffix: &str, directory: bool) -> io::Result<PathBuf> {
+    if prefix.contains('/') || suffix.contains('/') {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    for _ in 0..MAX_ATTEMPTS {
+        let path = dir.join(random_name(prefix, suffix)?);
+        let result = if directory {
+            DirBuilder::new().mode(0o700).create(&path)
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .map(|_| ())
+        };
+        match result {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::from(io::ErrorKind::AlreadyExists))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_make_temp() {
+        let dir = TestDir::new("temp");
+
+        let sub = make_temp(dir.path(), "pre_", "_suf", true).unwrap();
+        let file = make_temp(&sub, "f", ".txt", false).unwrap();
+        let other = make_temp(&sub, "f", ".txt", false).unwrap();
+
+        assert_ne!(file, other);
+        assert!(sub.is_dir());
+        assert!(file.is_file());
+        let name = sub.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("pre_") && name.ends_with("_suf"));
+        assert!(file.to_str().unwrap().ends_with(".txt"));
+        assert_eq!(sub.metadata().unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(file.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            make_temp(dir.path(), "a/b", "", false).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
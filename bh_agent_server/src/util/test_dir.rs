@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+
+/// A scratch directory for a test. It's removed when dropped, so failing tests clean up too.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates an empty directory. The name has to be unique across the crate's tests, since they
+    /// run in parallel.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("bh_agent_{}_test_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,505 @@
+use std::io::{ErrorKind, Read};
+
+use bh_agent_common::AgentError::{TextDecodeError, TextEncodeError};
+use bh_agent_common::{AgentError, FileOpenType, NewlineMode, TextEncoding, TextErrors};
+
+const LINE_CHUNK_SIZE: usize = 8192;
+
+// Binary files are read one byte per character, with lines ending at \n
+fn newline_mode(file_type: FileOpenType) -> NewlineMode {
+    match file_type {
+        FileOpenType::Binary => NewlineMode::Lf,
+        FileOpenType::Text { newline, .. } => newline,
+    }
+}
+
+fn backslash_escape(c: u32) -> String {
+    match c {
+        0..=0xff => format!("\\x{:02x}", c),
+        0x100..=0xffff => format!("\\u{:04x}", c),
+        _ => format!("\\U{:08x}", c),
+    }
+}
+
+// Decodes the first character in bytes onto the end of out as UTF-8, and returns the number of
+// bytes it took up. Returns None if more bytes are needed to tell.
+fn decode_one(
+    file_type: FileOpenType,
+    bytes: &[u8],
+    last: bool,
+    out: &mut Vec<u8>,
+) -> Result<Option<usize>, AgentError> {
+    let Some(&first) = bytes.first() else {
+        return Ok(None);
+    };
+    let FileOpenType::Text {
+        encoding, errors, ..
+    } = file_type
+    else {
+        out.push(first);
+        return Ok(Some(1));
+    };
+
+    let (decoded, len) = match encoding {
+        TextEncoding::Latin1 => (Some(char::from(first)), 1),
+        TextEncoding::Ascii => (first.is_ascii().then_some(char::from(first)), 1),
+        TextEncoding::Utf8 => {
+            let head = &bytes[..bytes.len().min(4)];
+            match std::str::from_utf8(head) {
+                Ok(s) => (s.chars().next(), s.chars().next().map_or(1, char::len_utf8)),
+                Err(e) if e.valid_up_to() > 0 => {
+                    let c = std::str::from_utf8(&head[..e.valid_up_to()])
+                        .ok()
+                        .and_then(|s| s.chars().next());
+                    (c, c.map_or(1, char::len_utf8))
+                }
+                Err(e) => match e.error_len() {
+                    Some(len) => (None, len),
+                    // A truncated sequence at the end of the file is a single error
+                    None if last => (None, head.len()),
+                    None => return Ok(None),
+                },
+            }
+        }
+    };
+
+    match (decoded, errors) {
+        (Some(c), _) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        (None, TextErrors::Strict) => return Err(TextDecodeError),
+        (None, TextErrors::Ignore) => {}
+        (None, TextErrors::Replace) => out.extend_from_slice(
+            char::REPLACEMENT_CHARACTER
+                .encode_utf8(&mut [0; 4])
+                .as_bytes(),
+        ),
+        (None, TextErrors::BackslashReplace) => {
+            for b in &bytes[..len] {
+                out.extend_from_slice(backslash_escape(*b as u32).as_bytes());
+            }
+        }
+    }
+    Ok(Some(len))
+}
+
+/// Encodes text sent by a client as UTF-8 for writing to a file opened with the given type.
+pub fn encode_text(data: &[u8], file_type: FileOpenType) -> Result<Vec<u8>, AgentError> {
+    let FileOpenType::Text {
+        encoding,
+        errors,
+        newline,
+    } = file_type
+    else {
+        return Ok(data.to_vec());
+    };
+    let text = std::str::from_utf8(data).map_err(|_| TextEncodeError)?;
+    let text = match newline {
+        NewlineMode::Universal | NewlineMode::Untranslated | NewlineMode::Lf => text.to_string(),
+        NewlineMode::Cr => text.replace('\n', "\r"),
+        NewlineMode::CrLf => text.replace('\n', "\r\n"),
+    };
+    if encoding == TextEncoding::Utf8 {
+        return Ok(text.into_bytes());
+    }
+
+    let limit = match encoding {
+        TextEncoding::Latin1 => 0xff,
+        _ => 0x7f,
+    };
+    let mut encoded = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match (c as u32, errors) {
+            (c, _) if c <= limit => encoded.push(c as u8),
+            (_, TextErrors::Strict) => return Err(TextEncodeError),
+            (_, TextErrors::Ignore) => {}
+            (_, TextErrors::Replace) => encoded.push(b'?'),
+            (c, TextErrors::BackslashReplace) => {
+                encoded.extend_from_slice(backslash_escape(c).as_bytes())
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+/// Reads characters and lines the way Python's file objects do. Text is decoded and handed back
+/// as UTF-8. Anything read past the end of a request is returned by `into_unconsumed`, so the
+/// caller can put it back.
+pub struct TextReader<'a, R: Read> {
+    inner: &'a mut R,
+    file_type: FileOpenType,
+    buffer: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    // A universal newline ended on a \r, so a \n that comes next belongs to it
+    pending_cr: bool,
+}
+
+impl<'a, R: Read> TextReader<'a, R> {
+    /// `pending` holds bytes that come before anything still in `inner`, and `pending_cr` is what
+    /// `pending_cr` returned after the previous read.
+    pub fn new(
+        inner: &'a mut R,
+        file_type: FileOpenType,
+        pending: Vec<u8>,
+        pending_cr: bool,
+    ) -> Self {
+        Self {
+            inner,
+            file_type,
+            buffer: pending,
+            pos: 0,
+            eof: false,
+            pending_cr,
+        }
+    }
+
+    /// Whether the next read has to skip a leading \n, since it's part of a newline that was
+    /// already returned.
+    pub fn pending_cr(&self) -> bool {
+        self.pending_cr
+    }
+
+    pub fn into_unconsumed(mut self) -> Vec<u8> {
+        self.buffer.split_off(self.pos)
+    }
+
+    fn fill(&mut self, want: usize) -> Result<(), AgentError> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + want.max(1), 0);
+        loop {
+            match self.inner.read(&mut self.buffer[start..]) {
+                Ok(n) => {
+                    self.buffer.truncate(start + n);
+                    self.eof = n == 0;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    // Every supported encoding is ASCII compatible, so a run of ASCII bytes decodes to itself.
+    // Appends up to max of them that can't be part of a newline, and returns how many it took.
+    fn take_plain_run(&mut self, max: usize, out: &mut Vec<u8>) -> usize {
+        if self.pending_cr {
+            return 0;
+        }
+        let available = &self.buffer[self.pos..];
+        let len = available
+            .iter()
+            .take(max)
+            .take_while(|b| b.is_ascii() && **b != b'\r' && **b != b'\n')
+            .count();
+        out.extend_from_slice(&available[..len]);
+        self.pos += len;
+        len
+    }
+
+    // Decodes the next character onto the end of out, returning false at the end of the file
+    fn next_unit(&mut self, want: usize, out: &mut Vec<u8>) -> Result<bool, AgentError> {
+        loop {
+            if self.pending_cr && self.pos < self.buffer.len() {
+                self.pending_cr = false;
+                if self.buffer[self.pos] == b'\n' {
+                    self.pos += 1;
+                }
+            }
+            let available = &self.buffer[self.pos..];
+            if let Some(len) = decode_one(self.file_type, available, self.eof, out)? {
+                self.pos += len;
+                return Ok(true);
+            }
+            if self.eof {
+                return Ok(false);
+            }
+            self.fill(want)?;
+        }
+    }
+
+    // Every supported encoding is ASCII compatible, so a \n following a \r is always one byte
+    fn skip_lf(&mut self) -> Result<bool, AgentError> {
+        if self.pos == self.buffer.len() && !self.eof {
+            self.fill(1)?;
+        }
+        let found = self.buffer.get(self.pos) == Some(&b'\n');
+        if found {
+            self.pos += 1;
+        }
+        Ok(found)
+    }
+
+    // Decodes the next character onto the end of out after newline translation, and returns
+    // whether it ends a line. A \r\n pair is only joined if there is room for both characters.
+    fn next_char(
+        &mut self,
+        want: usize,
+        room: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<Option<bool>, AgentError> {
+        let start = out.len();
+        if !self.next_unit(want, out)? {
+            return Ok(None);
+        }
+        let is_cr = out[start..] == *b"\r";
+        let is_lf = out[start..] == *b"\n";
+        Ok(Some(match newline_mode(self.file_type) {
+            NewlineMode::Universal if is_cr => {
+                out[start] = b'\n';
+                // Like Python's TextIOWrapper, the line doesn't wait for a \n that hasn't
+                // arrived yet. It's skipped by whichever read sees it.
+                match self.pos == self.buffer.len() {
+                    true => self.pending_cr = !self.eof,
+                    false => {
+                        self.skip_lf()?;
+                    }
+                }
+                true
+            }
+            NewlineMode::Untranslated | NewlineMode::CrLf if is_cr => {
+                if room > 1 && self.skip_lf()? {
+                    out.push(b'\n');
+                    true
+                } else {
+                    newline_mode(self.file_type) == NewlineMode::Untranslated
+                }
+            }
+            NewlineMode::Universal | NewlineMode::Untranslated | NewlineMode::Lf => is_lf,
+            NewlineMode::Cr => is_cr,
+            NewlineMode::CrLf => false,
+        }))
+    }
+
+    fn char_count(&self, data: &[u8]) -> usize {
+        match self.file_type {
+            FileOpenType::Binary => data.len(),
+            FileOpenType::Text { .. } => data.iter().filter(|b| (*b & 0xc0) != 0x80).count(),
+        }
+    }
+
+    /// Reads up to n characters, only returning fewer at the end of the file.
+    pub fn read_chars(&mut self, n: usize) -> Result<Vec<u8>, AgentError> {
+        let mut result = Vec::new();
+        let mut count = 0;
+        while count < n {
+            count += self.take_plain_run(n - count, &mut result);
+            if count == n {
+                break;
+            }
+            let start = result.len();
+            if self.next_char(n - count, n - count, &mut result)?.is_none() {
+                break;
+            }
+            count += self.char_count(&result[start..]);
+        }
+        Ok(result)
+    }
+
+    /// Reads a line including its terminator, stopping early after limit characters.
+    pub fn read_line(&mut self, limit: Option<usize>) -> Result<Vec<u8>, AgentError> {
+        let mut line = Vec::new();
+        let mut count = 0;
+        while limit.is_none_or(|limit| count < limit) {
+            let room = limit.map_or(usize::MAX, |limit| limit - count);
+            count += self.take_plain_run(room, &mut line);
+            if limit == Some(count) {
+                break;
+            }
+            let room = limit.map_or(usize::MAX, |limit| limit - count);
+            let start = line.len();
+            let Some(ends_line) = self.next_char(LINE_CHUNK_SIZE, room, &mut line)? else {
+                break;
+            };
+            count += self.char_count(&line[start..]);
+            if ends_line {
+                break;
+            }
+        }
+        Ok(line)
+    }
+
+    /// Reads lines until the end of the file, or until they add up to at least hint characters
+    /// when hint isn't zero.
+    pub fn read_lines(&mut self, hint: usize) -> Result<Vec<Vec<u8>>, AgentError> {
+        let mut lines = Vec::new();
+        let mut total = 0;
+        loop {
+            let line = self.read_line(None)?;
+            if line.is_empty() {
+                break;
+            }
+            total += self.char_count(&line);
+            lines.push(line);
+            if hint > 0 && total >= hint {
+                break;
+            }
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn text(encoding: TextEncoding, errors: TextErrors, newline: NewlineMode) -> FileOpenType {
+        FileOpenType::Text {
+            encoding,
+            errors,
+            newline,
+        }
+    }
+
+    fn utf8(newline: NewlineMode) -> FileOpenType {
+        text(TextEncoding::Utf8, TextErrors::Strict, newline)
+    }
+
+    fn lines(data: &[u8], file_type: FileOpenType) -> Vec<Vec<u8>> {
+        let mut cursor = Cursor::new(data);
+        TextReader::new(&mut cursor, file_type, Vec::new(), false)
+            .read_lines(0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_single_byte_chars() {
+        let mut cursor = Cursor::new("abcdef".as_bytes());
+        let mut reader =
+            TextReader::new(&mut cursor, utf8(NewlineMode::Universal), Vec::new(), false);
+        assert_eq!(reader.read_chars(3).unwrap(), b"abc");
+        assert_eq!(reader.into_unconsumed(), b"");
+    }
+
+    #[test]
+    fn test_multi_byte_chars() {
+        let mut cursor = Cursor::new("a😀b😂c".as_bytes());
+        let mut reader =
+            TextReader::new(&mut cursor, utf8(NewlineMode::Universal), Vec::new(), false);
+        assert_eq!(reader.read_chars(2).unwrap(), "a😀".as_bytes());
+        assert_eq!(reader.read_chars(2).unwrap(), "b😂".as_bytes());
+        assert_eq!(reader.read_chars(2).unwrap(), b"c");
+        assert_eq!(reader.read_chars(2).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_newline_modes() {
+        let data = b"a\nb\r\nc\rd\n\ne";
+        assert_eq!(
+            lines(data, utf8(NewlineMode::Universal)),
+            vec![&b"a\n"[..], b"b\n", b"c\n", b"d\n", b"\n", b"e"]
+        );
+        assert_eq!(
+            lines(data, utf8(NewlineMode::Untranslated)),
+            vec![&b"a\n"[..], b"b\r\n", b"c\r", b"d\n", b"\n", b"e"]
+        );
+        assert_eq!(
+            lines(data, utf8(NewlineMode::Lf)),
+            vec![&b"a\n"[..], b"b\r\n", b"c\rd\n", b"\n", b"e"]
+        );
+        assert_eq!(
+            lines(data, utf8(NewlineMode::Cr)),
+            vec![&b"a\nb\r"[..], b"\nc\r", b"d\n\ne"]
+        );
+        assert_eq!(
+            lines(data, utf8(NewlineMode::CrLf)),
+            vec![&b"a\nb\r\n"[..], b"c\rd\n\ne"]
+        );
+        assert_eq!(
+            lines(data, FileOpenType::Binary),
+            vec![&b"a\n"[..], b"b\r\n", b"c\rd\n", b"\n", b"e"]
+        );
+    }
+
+    // Hands out one chunk per read, and fails the test if it has to wait for more
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            assert!(!self.0.is_empty(), "read blocked");
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn test_pending_cr() {
+        let mut chunks = Chunks(vec![b"a\r"]);
+        let mut reader =
+            TextReader::new(&mut chunks, utf8(NewlineMode::Universal), Vec::new(), false);
+        assert_eq!(reader.read_line(None).unwrap(), b"a\n");
+        assert!(reader.pending_cr());
+
+        let mut chunks = Chunks(vec![b"\nb\r", b"c"]);
+        let mut reader =
+            TextReader::new(&mut chunks, utf8(NewlineMode::Universal), Vec::new(), true);
+        assert_eq!(reader.read_line(None).unwrap(), b"b\n");
+        assert_eq!(reader.read_chars(1).unwrap(), b"c");
+        assert!(!reader.pending_cr());
+    }
+
+    #[test]
+    fn test_read_line_limit_and_hint() {
+        let mut cursor = Cursor::new("héllo\nworld\nagain\n".as_bytes());
+        let mut reader =
+            TextReader::new(&mut cursor, utf8(NewlineMode::Universal), Vec::new(), false);
+        assert_eq!(reader.read_line(Some(2)).unwrap(), "hé".as_bytes());
+        assert_eq!(reader.read_line(None).unwrap(), b"llo\n");
+        assert_eq!(reader.read_lines(3).unwrap(), vec![b"world\n".to_vec()]);
+        assert_eq!(reader.into_unconsumed(), b"again\n");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let data = b"a\xe9b\xf0\x9f";
+        let read = |encoding, errors| {
+            let mut cursor = Cursor::new(&data[..]);
+            TextReader::new(
+                &mut cursor,
+                text(encoding, errors, NewlineMode::Universal),
+                Vec::new(),
+                false,
+            )
+            .read_chars(100)
+        };
+        assert_eq!(
+            read(TextEncoding::Latin1, TextErrors::Strict).unwrap(),
+            "aébð\u{9f}".as_bytes()
+        );
+        assert!(matches!(
+            read(TextEncoding::Utf8, TextErrors::Strict),
+            Err(TextDecodeError)
+        ));
+        assert_eq!(read(TextEncoding::Utf8, TextErrors::Ignore).unwrap(), b"ab");
+        assert_eq!(
+            read(TextEncoding::Utf8, TextErrors::Replace).unwrap(),
+            "a\u{fffd}b\u{fffd}".as_bytes()
+        );
+        assert_eq!(
+            read(TextEncoding::Ascii, TextErrors::BackslashReplace).unwrap(),
+            b"a\\xe9b\\xf0\\x9f"
+        );
+    }
+
+    #[test]
+    fn test_encode_text() {
+        let latin1 = text(TextEncoding::Latin1, TextErrors::Strict, NewlineMode::CrLf);
+        assert_eq!(encode_text("é\n".as_bytes(), latin1).unwrap(), b"\xe9\r\n");
+        assert!(matches!(
+            encode_text("😀".as_bytes(), latin1),
+            Err(TextEncodeError)
+        ));
+        let ascii = text(
+            TextEncoding::Ascii,
+            TextErrors::BackslashReplace,
+            NewlineMode::Universal,
+        );
+        assert_eq!(
+            encode_text("é😀\n".as_bytes(), ascii).unwrap(),
+            b"\\xe9\\U0001f600\n"
+        );
+    }
+}
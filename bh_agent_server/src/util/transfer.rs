@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions, Permissions};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use bh_agent_common::AgentError::{HashMismatch, InvalidOffset, NotARegularFile, NotFound};
+use bh_agent_common::{AgentError, FileChunk, FileTransferInfo, HashAlgorithm};
+
+use crate::util::{check_sha256, futime, hash_file, open_jailed, read_full_at};
+
+/// Larger download requests are cut down to this size
+pub const MAX_TRANSFER_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+// The partial file is keyed by the hash of the finished file, so uploads of different contents to
+// the same path don't write over each other
+fn partial_path(path: &str, sha256: &str) -> Result<String, AgentError> {
+    check_sha256(sha256)?;
+    Ok(format!("{}.{}.bh_partial", path, sha256))
+}
+
+// The partial file sits next to the destination, so it's confined to the same roots. It's never
+// followed if it's a symlink, and once open it's only used through the file.
+fn open_partial(roots: &[PathBuf], partial: &str, flags: libc::c_int) -> Result<File, AgentError> {
+    open_jailed(roots, partial, flags | libc::O_NOFOLLOW)
+}
+
+pub fn upload_status(roots: &[PathBuf], path: &str, sha256: &str) -> Result<u64, AgentError> {
+    match open_partial(roots, &partial_path(path, sha256)?, libc::O_RDONLY) {
+        Ok(file) => Ok(file.metadata()?.len()),
+        Err(NotFound) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn upload_chunk(
+    roots: &[PathBuf],
+    path: &str,
+    sha256: &str,
+    chunk: &FileChunk,
+) -> Result<u64, AgentError> {
+    let partial = partial_path(path, sha256)?;
+    let file = open_partial(roots, &partial, libc::O_WRONLY | libc::O_CREAT)?;
+    if chunk.offset > file.metadata()?.len() {
+        return Err(InvalidOffset);
+    }
+    file.set_len(chunk.offset)?;
+    file.write_all_at(&chunk.data, chunk.offset)?;
+    Ok(chunk.offset + chunk.data.len() as u64)
+}
+
+pub fn upload_finish(
+    roots: &[PathBuf],
+    path: &str,
+    info: &FileTransferInfo,
+) -> Result<(), AgentError> {
+    let partial = partial_path(path, &info.sha256)?;
+    let file = open_partial(roots, &partial, libc::O_RDONLY)?;
+    if file.metadata()?.len() != info.size
+        || hash_file(&file, HashAlgorithm::Sha256, None)? != info.sha256
+    {
+        std::fs::remove_file(&partial)?;
+        return Err(HashMismatch);
+    }
+    file.set_permissions(Permissions::from_mode(info.mode & 0o7777))?;
+    futime(&file, None, Some(info.mtime_ns))?;
+    // rename() replaces a symlink at the destination rather than following it
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+// Opening a FIFO for reading would wait for a writer, so anything but a regular file is refused
+// first. The open doesn't block either, in case the path is swapped for a FIFO in between.
+fn open_regular(path: &str) -> Result<File, AgentError> {
+    if !std::fs::metadata(path)?.is_file() {
+        return Err(NotARegularFile);
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    match file.metadata()?.is_file() {
+        true => Ok(file),
+        false => Err(NotARegularFile),
+    }
+}
+
+pub fn download_info(path: &str) -> Result<FileTransferInfo, AgentError> {
+    let file = open_regular(path)?;
+    let metadata = file.metadata()?;
+    Ok(FileTransferInfo {
+        size: metadata.len(),
+        mode: metadata.mode() & 0o7777,
+        mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+        sha256: hash_file(&file, HashAlgorithm::Sha256, None)?,
+    })
+}
+
+pub fn download_chunk(path: &str, offset: u64, length: u64) -> Result<FileChunk, AgentError> {
+    let file = open_regular(path)?;
+    let data = read_full_at(&file, offset, length.min(MAX_TRANSFER_CHUNK_SIZE) as usize)?;
+    Ok(FileChunk { offset, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use bh_agent_common::AgentError::InvalidHash;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_upload_and_download() {
+        let dir = TestDir::new("transfer");
+        let path = dir.join("file");
+        let path = path.to_str().unwrap();
+        let content = b"hello, world";
+        let info = FileTransferInfo {
+            size: content.len() as u64,
+            mode: 0o751,
+            mtime_ns: 1_234_000_000_567,
+            sha256: format!("{:x}", Sha256::digest(content)),
+        };
+
+        assert_eq!(upload_status(&[], path, &info.sha256).unwrap(), 0);
+        let chunk = |offset: usize, data: &[u8]| FileChunk {
+            offset: offset as u64,
+            data: data.to_vec(),
+        };
+        assert_eq!(
+            upload_chunk(&[], path, &info.sha256, &chunk(0, b"hello, XXXXXX")).unwrap(),
+            13
+        );
+        assert!(matches!(
+            upload_chunk(&[], path, &info.sha256, &chunk(20, b"late")),
+            Err(InvalidOffset)
+        ));
+        // Resuming from an earlier offset discards the rest of the partial file
+        assert_eq!(
+            upload_chunk(&[], path, &info.sha256, &chunk(7, b"wor")).unwrap(),
+            10
+        );
+        assert_eq!(upload_status(&[], path, &info.sha256).unwrap(), 10);
+        assert!(matches!(upload_finish(&[], path, &info), Err(HashMismatch)));
+        assert_eq!(upload_status(&[], path, &info.sha256).unwrap(), 0);
+
+        upload_chunk(&[], path, &info.sha256, &chunk(0, b"hello, ")).unwrap();
+        upload_chunk(&[], path, &info.sha256, &chunk(7, b"world")).unwrap();
+        // Another upload to the same path has its own partial file
+        let other = format!("{:x}", Sha256::digest(b"other"));
+        upload_chunk(&[], path, &other, &chunk(0, b"other")).unwrap();
+        upload_finish(&[], path, &info).unwrap();
+        assert_eq!(upload_status(&[], path, &other).unwrap(), 5);
+        assert!(matches!(upload_status(&[], path, "../x"), Err(InvalidHash)));
+        assert_eq!(std::fs::read(path).unwrap(), content);
+
+        let downloaded = download_info(path).unwrap();
+        assert_eq!(downloaded.size, info.size);
+        assert_eq!(downloaded.mode, info.mode);
+        assert_eq!(downloaded.mtime_ns, info.mtime_ns);
+        assert_eq!(downloaded.sha256, info.sha256);
+        assert_eq!(download_chunk(path, 7, 100).unwrap().data, b"world");
+        assert!(download_chunk(path, 12, 100).unwrap().data.is_empty());
+
+        let fifo = dir.join("fifo");
+        let fifo_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o600) }, 0);
+        assert!(matches!(
+            download_info(fifo.to_str().unwrap()),
+            Err(NotARegularFile)
+        ));
+    }
+
+    #[test]
+    fn test_upload_partial_symlink() {
+        let dir = TestDir::new("transfer_symlink");
+        let inside = dir.join("inside");
+        std::fs::create_dir(&inside).unwrap();
+        let roots = vec![inside.clone()];
+        let outside = dir.join("outside");
+        std::fs::write(&outside, b"").unwrap();
+        let path = inside.join("file");
+        let path = path.to_str().unwrap();
+        let sha256 = format!("{:x}", Sha256::digest(b"data"));
+        let chunk = FileChunk {
+            offset: 0,
+            data: b"data".to_vec(),
+        };
+
+        // A partial file planted as a symlink out of the roots isn't written through
+        std::os::unix::fs::symlink(&outside, partial_path(path, &sha256).unwrap()).unwrap();
+        assert!(upload_chunk(&roots, path, &sha256, &chunk).is_err());
+        assert!(upload_status(&roots, path, &sha256).is_err());
+        assert_eq!(std::fs::read(&outside).unwrap(), b"");
+
+        // Nor is one pointing inside them
+        let target = inside.join("target");
+        std::fs::write(&target, b"").unwrap();
+        std::fs::remove_file(partial_path(path, &sha256).unwrap()).unwrap();
+        std::os::unix::fs::symlink(&target, partial_path(path, &sha256).unwrap()).unwrap();
+        assert!(upload_chunk(&roots, path, &sha256, &chunk).is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"");
+    }
+}
@@ -0,0 +1,77 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+use bh_agent_common::AgentError;
+use bh_agent_common::AgentError::IoError;
+
+fn timespec(time_ns: Option<i64>) -> libc::timespec {
+    match time_ns {
+        Some(ns) => libc::timespec {
+            tv_sec: ns.div_euclid(1_000_000_000),
+            tv_nsec: ns.rem_euclid(1_000_000_000),
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+    }
+}
+
+/// Sets the access and modification times of a path, in nanoseconds since the Unix epoch. A time
+/// that isn't given is set to the current time, like os.utime.
+pub fn utime(
+    path: &str,
+    atime_ns: Option<i64>,
+    mtime_ns: Option<i64>,
+    follow_symlinks: bool,
+) -> Result<(), AgentError> {
+    let path = CString::new(path).map_err(|_| IoError)?;
+    let times = [timespec(atime_ns), timespec(mtime_ns)];
+    let flags = match follow_symlinks {
+        true => 0,
+        false => libc::AT_SYMLINK_NOFOLLOW,
+    };
+    match unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), flags) } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        _ => Ok(()),
+    }
+}
+
+/// Like utime, but for an open file
+pub fn futime(file: &File, atime_ns: Option<i64>, mtime_ns: Option<i64>) -> Result<(), AgentError> {
+    let times = [timespec(atime_ns), timespec(mtime_ns)];
+    match unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_utime() {
+        let dir = TestDir::new("utime");
+        let path = dir.join("file");
+        std::fs::write(&path, b"").unwrap();
+        let path_str = path.to_str().unwrap();
+
+        utime(path_str, Some(1_000_000_123), Some(-1_500_000_000), true).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.atime(), metadata.atime_nsec()), (1, 123));
+        assert_eq!((metadata.mtime(), metadata.mtime_nsec()), (-2, 500_000_000));
+
+        utime(path_str, None, None, true).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().mtime() > 0);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            utime(path_str, None, None, true),
+            Err(AgentError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,240 @@
+use std::ffi::CString;
+
+use bh_agent_common::AgentError::{InvalidCapabilities, IoError, NoSuchAttribute};
+use bh_agent_common::{AgentError, FileCapabilities};
+
+const CAPABILITY_XATTR: &str = "security.capability";
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+fn c_string(s: &str) -> Result<CString, AgentError> {
+    CString::new(s).map_err(|_| IoError)
+}
+
+fn xattr_error() -> AgentError {
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENODATA) => NoSuchAttribute,
+        _ => err.into(),
+    }
+}
+
+// Calls a list or get function twice, once to size the buffer and once to fill it. The value can
+// grow in between, in which case it starts over.
+fn read_sized(read: impl Fn(*mut libc::c_void, usize) -> isize) -> Result<Vec<u8>, AgentError> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(xattr_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let read_size = read(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if read_size >= 0 {
+            buffer.truncate(read_size as usize);
+            return Ok(buffer);
+        }
+        if std::io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return Err(xattr_error());
+        }
+    }
+}
+
+pub fn list_xattrs(path: &str, follow_symlinks: bool) -> Result<Vec<String>, AgentError> {
+    let path = c_string(path)?;
+    let names = read_sized(|buf, size| unsafe {
+        match follow_symlinks {
+            true => libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, size),
+            false => libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size),
+        }
+    })?;
+    let mut names: Vec<String> = names
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+pub fn get_xattr(path: &str, name: &str, follow_symlinks: bool) -> Result<Vec<u8>, AgentError> {
+    let path = c_string(path)?;
+    let name = c_string(name)?;
+    read_sized(|buf, size| unsafe {
+        match follow_symlinks {
+            true => libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size),
+            false => libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size),
+        }
+    })
+}
+
+pub fn set_xattr(
+    path: &str,
+    name: &str,
+    value: &[u8],
+    follow_symlinks: bool,
+) -> Result<(), AgentError> {
+    let path = c_string(path)?;
+    let name = c_string(name)?;
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    let result = unsafe {
+        match follow_symlinks {
+            true => libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0),
+            false => libc::lsetxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0),
+        }
+    };
+    match result {
+        -1 => Err(xattr_error()),
+        _ => Ok(()),
+    }
+}
+
+pub fn remove_xattr(path: &str, name: &str, follow_symlinks: bool) -> Result<(), AgentError> {
+    let path = c_string(path)?;
+    let name = c_string(name)?;
+    let result = unsafe {
+        match follow_symlinks {
+            true => libc::removexattr(path.as_ptr(), name.as_ptr()),
+            false => libc::lremovexattr(path.as_ptr(), name.as_ptr()),
+        }
+    };
+    match result {
+        -1 => Err(xattr_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes a vfs_cap_data structure, as stored in the security.capability attribute.
+pub fn decode_capabilities(data: &[u8]) -> Result<FileCapabilities, AgentError> {
+    let words: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    let Some(&magic) = words.first() else {
+        return Err(InvalidCapabilities);
+    };
+    let expected_words = match magic & VFS_CAP_REVISION_MASK {
+        VFS_CAP_REVISION_1 => 3,
+        VFS_CAP_REVISION_2 => 5,
+        VFS_CAP_REVISION_3 => 6,
+        _ => return Err(InvalidCapabilities),
+    };
+    if data.len() != expected_words * 4 {
+        return Err(InvalidCapabilities);
+    }
+    // Each set is split into 32 bit halves, low half first
+    let set = |index: usize| {
+        (0..(expected_words - 1) / 2)
+            .map(|half| (words[1 + half * 2 + index] as u64) << (32 * half))
+            .fold(0, |set, half| set | half)
+    };
+    Ok(FileCapabilities {
+        permitted: set(0),
+        inheritable: set(1),
+        effective: magic & VFS_CAP_FLAGS_EFFECTIVE != 0,
+        rootid: (expected_words == 6).then(|| words[5]),
+    })
+}
+
+/// Encodes capabilities as revision 2, or revision 3 if they are tied to a user namespace.
+pub fn encode_capabilities(caps: &FileCapabilities) -> Vec<u8> {
+    let revision = match caps.rootid {
+        Some(_) => VFS_CAP_REVISION_3,
+        None => VFS_CAP_REVISION_2,
+    };
+    let flags = match caps.effective {
+        true => VFS_CAP_FLAGS_EFFECTIVE,
+        false => 0,
+    };
+    let words = [
+        revision | flags,
+        caps.permitted as u32,
+        caps.inheritable as u32,
+        (caps.permitted >> 32) as u32,
+        (caps.inheritable >> 32) as u32,
+    ];
+    words
+        .iter()
+        .chain(caps.rootid.iter())
+        .flat_map(|word| word.to_le_bytes())
+        .collect()
+}
+
+/// Returns the file capabilities of a path, or None if it has none.
+pub fn get_capabilities(path: &str) -> Result<Option<FileCapabilities>, AgentError> {
+    match get_xattr(path, CAPABILITY_XATTR, true) {
+        Ok(data) => decode_capabilities(&data).map(Some),
+        Err(NoSuchAttribute) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sets the file capabilities of a path, or removes them if None is given.
+pub fn set_capabilities(path: &str, caps: Option<&FileCapabilities>) -> Result<(), AgentError> {
+    match caps {
+        Some(caps) => set_xattr(path, CAPABILITY_XATTR, &encode_capabilities(caps), true),
+        None => match remove_xattr(path, CAPABILITY_XATTR, true) {
+            Err(NoSuchAttribute) => Ok(()),
+            result => result,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_capabilities() {
+        // cap_net_raw+ep, as written by setcap
+        let data = [
+            0x01, 0, 0, 0x02, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let caps = decode_capabilities(&data).unwrap();
+        assert_eq!(caps.permitted, 1 << 13);
+        assert_eq!(caps.inheritable, 0);
+        assert!(caps.effective);
+        assert_eq!(caps.rootid, None);
+        assert_eq!(encode_capabilities(&caps), data);
+
+        let caps = FileCapabilities {
+            permitted: 1 << 40 | 1,
+            inheritable: 1 << 33,
+            effective: false,
+            rootid: Some(100000),
+        };
+        let encoded = encode_capabilities(&caps);
+        assert_eq!(encoded.len(), 24);
+        assert_eq!(decode_capabilities(&encoded).unwrap(), caps);
+
+        assert!(matches!(
+            decode_capabilities(&data[..16]),
+            Err(InvalidCapabilities)
+        ));
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let dir = TestDir::new("xattr");
+        let path = dir.join("file");
+        std::fs::write(&path, b"").unwrap();
+        let path = path.to_str().unwrap();
+
+        set_xattr(path, "user.first", b"one", true).unwrap();
+        set_xattr(path, "user.second", b"", true).unwrap();
+        assert_eq!(
+            list_xattrs(path, true).unwrap(),
+            vec!["user.first", "user.second"]
+        );
+        assert_eq!(get_xattr(path, "user.first", true).unwrap(), b"one");
+        remove_xattr(path, "user.first", true).unwrap();
+        assert!(matches!(
+            get_xattr(path, "user.first", true),
+            Err(NoSuchAttribute)
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bh_agent_common::{AgentError, WatchEvent, WatchEventKind};
+
+use crate::util::wait_readable;
+
+// Beyond this, events are dropped and an Overflow event is queued instead
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+// How often the reader thread checks whether the watch was removed, and a waiter whether its
+// request was cancelled
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+const EVENT_HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+fn event_mask(kind: WatchEventKind) -> u32 {
+    match kind {
+        WatchEventKind::Create => libc::IN_CREATE,
+        WatchEventKind::Modify => libc::IN_MODIFY,
+        WatchEventKind::Delete => libc::IN_DELETE,
+        WatchEventKind::MovedFrom => libc::IN_MOVED_FROM,
+        WatchEventKind::MovedTo => libc::IN_MOVED_TO,
+        WatchEventKind::CloseWrite => libc::IN_CLOSE_WRITE,
+        WatchEventKind::Overflow => 0,
+    }
+}
+
+fn event_kinds(mask: u32) -> impl Iterator<Item = WatchEventKind> {
+    [
+        WatchEventKind::Create,
+        WatchEventKind::Modify,
+        WatchEventKind::Delete,
+        WatchEventKind::MovedFrom,
+        WatchEventKind::MovedTo,
+        WatchEventKind::CloseWrite,
+    ]
+    .into_iter()
+    .filter(move |kind| mask & event_mask(*kind) != 0)
+}
+
+struct WatchQueue {
+    events: VecDeque<WatchEvent>,
+    overflowed: bool,
+}
+
+struct WatchShared {
+    queue: Mutex<WatchQueue>,
+    ready: Condvar,
+    stop: AtomicBool,
+}
+
+impl WatchShared {
+    fn push(&self, event: WatchEvent) {
+        let Ok(mut queue) = self.queue.lock() else {
+            return;
+        };
+        if queue.events.len() < MAX_QUEUED_EVENTS {
+            queue.events.push_back(event);
+        } else if !queue.overflowed {
+            queue.overflowed = true;
+            queue.events.push_back(WatchEvent {
+                kind: WatchEventKind::Overflow,
+                path: String::new(),
+                is_dir: false,
+                cookie: 0,
+            });
+        }
+        self.ready.notify_all();
+    }
+}
+
+struct Inotify {
+    file: File,
+    recursive: bool,
+    // The events the client asked for
+    mask: u32,
+    paths: HashMap<i32, PathBuf>,
+}
+
+impl Inotify {
+    fn add_watch(&mut self, path: &Path) -> std::io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // New directories always have to be seen to watch them recursively
+        let mask = match self.recursive {
+            true => self.mask | libc::IN_CREATE | libc::IN_MOVED_TO,
+            false => self.mask,
+        };
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                std::os::fd::AsRawFd::as_raw_fd(&self.file),
+                c_path.as_ptr(),
+                mask | libc::IN_DELETE_SELF,
+            )
+        };
+        if wd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.paths.insert(wd, path.to_path_buf());
+        Ok(())
+    }
+
+    // Watches every directory below the path. Entries that already exist were created before the
+    // watch could see them, so Create events are made up for them when new_dir is set.
+    fn add_subtree(&mut self, dir: &Path, new_dir: bool, found: &mut Vec<WatchEvent>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if new_dir && self.mask & libc::IN_CREATE != 0 {
+                found.push(WatchEvent {
+                    kind: WatchEventKind::Create,
+                    path: entry.path().to_string_lossy().into_owned(),
+                    is_dir,
+                    cookie: 0,
+                });
+            }
+            if is_dir && self.add_watch(&entry.path()).is_ok() {
+                self.add_subtree(&entry.path(), new_dir, found);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, wd: i32, mask: u32, cookie: u32, name: &[u8], shared: &WatchShared) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            if let Ok(mut queue) = shared.queue.lock() {
+                queue.overflowed = false;
+            }
+            shared.push(WatchEvent {
+                kind: WatchEventKind::Overflow,
+                path: String::new(),
+                is_dir: false,
+                cookie: 0,
+            });
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            self.paths.remove(&wd);
+            return;
+        }
+        let Some(dir) = self.paths.get(&wd) else {
+            return;
+        };
+        let path = match name {
+            [] => dir.clone(),
+            name => dir.join(OsStr::from_bytes(name)),
+        };
+        let is_dir = mask & libc::IN_ISDIR != 0;
+        // A new directory is watched and scanned before its event is queued, so nothing a client
+        // does in it after seeing the event is missed. Entries created between the watch and the
+        // scan are reported by both, so they can show up twice.
+        let new_dir = self.recursive && is_dir && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+        let mut found = Vec::new();
+        if new_dir && self.add_watch(&path).is_ok() {
+            self.add_subtree(&path, true, &mut found);
+        }
+        for kind in event_kinds(mask & self.mask) {
+            shared.push(WatchEvent {
+                kind,
+                path: path.to_string_lossy().into_owned(),
+                is_dir,
+                cookie,
+            });
+        }
+        for event in found {
+            shared.push(event);
+        }
+    }
+
+    fn run(mut self, shared: Arc<WatchShared>) {
+        let mut buf = vec![0u8; 64 * 1024];
+        while !shared.stop.load(Ordering::Relaxed) {
+            match wait_readable(&self.file, Some(STOP_CHECK_INTERVAL)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => return,
+            }
+            let n = match self.file.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            let mut offset = 0;
+            while offset + EVENT_HEADER_SIZE <= n {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + EVENT_HEADER_SIZE;
+                let name_end = (name_start + event.len as usize).min(n);
+                let name = &buf[name_start..name_end];
+                // The name is padded with NULs
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                self.handle_event(event.wd, event.mask, event.cookie, name, &shared);
+                offset = name_end;
+            }
+        }
+    }
+}
+
+/// Watches a path with inotify. A background thread moves events into a queue until the watcher
+/// is dropped, so no events are missed between fetches.
+pub struct Watcher {
+    shared: Arc<WatchShared>,
+}
+
+impl Watcher {
+    pub fn new(path: &str, recursive: bool, kinds: &[WatchEventKind]) -> Result<Self, AgentError> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mask = match kinds.is_empty() {
+            true => {
+                libc::IN_CREATE
+                    | libc::IN_MODIFY
+                    | libc::IN_DELETE
+                    | libc::IN_MOVED_FROM
+                    | libc::IN_MOVED_TO
+                    | libc::IN_CLOSE_WRITE
+            }
+            false => kinds.iter().fold(0, |mask, kind| mask | event_mask(*kind)),
+        };
+        let shared = Arc::new(WatchShared {
+            queue: Mutex::new(WatchQueue {
+                events: VecDeque::new(),
+                overflowed: false,
+            }),
+            ready: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+        let mut inotify = Inotify {
+            file: unsafe { File::from_raw_fd(fd) },
+            recursive,
+            mask,
+            paths: HashMap::new(),
+        };
+        inotify.add_watch(Path::new(path))?;
+        if recursive {
+            inotify.add_subtree(Path::new(path), false, &mut Vec::new());
+        }
+        let thread_shared = shared.clone();
+        thread::spawn(move || inotify.run(thread_shared));
+        Ok(Self { shared })
+    }
+
+    /// Takes up to max_events events from the queue, waiting until there is at least one, the
+    /// timeout expires, the watch is stopped or `cancelled` is set.
+    pub fn events(
+        &self,
+        max_events: usize,
+        timeout: Option<Duration>,
+        cancelled: &AtomicBool,
+    ) -> Result<Vec<WatchEvent>, AgentError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut queue = self.shared.queue.lock()?;
+        while self.is_waiting(&queue) && !cancelled.load(Ordering::Acquire) {
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                break;
+            }
+            let wait = remaining.map_or(STOP_CHECK_INTERVAL, |r| r.min(STOP_CHECK_INTERVAL));
+            queue = self.shared.ready.wait_timeout(queue, wait)?.0;
+        }
+        let count = queue.events.len().min(max_events);
+        let events = queue.events.drain(..count).collect();
+        if queue.events.is_empty() {
+            queue.overflowed = false;
+        }
+        Ok(events)
+    }
+
+    fn is_waiting(&self, queue: &WatchQueue) -> bool {
+        queue.events.is_empty() && !self.shared.stop.load(Ordering::Relaxed)
+    }
+
+    /// Stops the reader thread and wakes up anything waiting for events.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        // Taking the lock makes sure a waiter has either seen the flag or is already waiting
+        let _queue = self.shared.queue.lock();
+        self.shared.ready.notify_all();
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    #[test]
+    fn test_watcher() {
+        let dir = TestDir::new("watch");
+        let watcher = Watcher::new(dir.path().to_str().unwrap(), true, &[]).unwrap();
+        let live = AtomicBool::new(false);
+
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        let mut events = watcher
+            .events(100, Some(Duration::from_secs(2)), &live)
+            .unwrap();
+        std::fs::write(dir.join("sub/file"), b"data").unwrap();
+        std::fs::rename(dir.join("sub/file"), dir.join("moved")).unwrap();
+
+        while !events
+            .iter()
+            .any(|e: &WatchEvent| e.kind == WatchEventKind::MovedTo)
+        {
+            let new = watcher
+                .events(100, Some(Duration::from_secs(2)), &live)
+                .unwrap();
+            assert!(!new.is_empty(), "timed out after {:?}", events);
+            events.extend(new);
+        }
+        let summary: Vec<(WatchEventKind, String)> = events
+            .iter()
+            .map(|e| {
+                let path = Path::new(&e.path).strip_prefix(dir.path()).unwrap();
+                (e.kind, path.to_string_lossy().into_owned())
+            })
+            .collect();
+        let expected = [
+            (WatchEventKind::Create, "sub"),
+            (WatchEventKind::Create, "sub/file"),
+            (WatchEventKind::Modify, "sub/file"),
+            (WatchEventKind::CloseWrite, "sub/file"),
+            (WatchEventKind::MovedFrom, "sub/file"),
+            (WatchEventKind::MovedTo, "moved"),
+        ];
+        assert_eq!(
+            summary,
+            expected.map(|(kind, path)| (kind, path.to_string()))
+        );
+        assert_eq!(events[4].cookie, events[5].cookie);
+
+        // A waiter without a timeout returns once its request is cancelled, or the watch is stopped
+        let watcher = Arc::new(watcher);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (waiter, flag) = (watcher.clone(), cancelled.clone());
+        let waiting = thread::spawn(move || waiter.events(100, None, &flag));
+        thread::sleep(Duration::from_millis(100));
+        cancelled.store(true, Ordering::Release);
+        assert!(waiting.join().unwrap().unwrap().is_empty());
+
+        let waiter = watcher.clone();
+        let waiting = thread::spawn(move || waiter.events(100, None, &AtomicBool::new(false)));
+        thread::sleep(Duration::from_millis(100));
+        watcher.stop();
+        assert!(waiting.join().unwrap().unwrap().is_empty());
+    }
+}